@@ -0,0 +1,291 @@
+use ndarray::{parallel::prelude::*, Array2, Axis};
+
+use crate::{
+    filter::{Antialias, Filter},
+    images::{self, ImageArray, Sample},
+    render::{Coverage, Sampling},
+};
+
+/// How far the entries of the matrix may be from whole numbers, which allows for the rounding
+/// errors of `--rotate 90` or `--pivot center`. It's absolute, so that large translations that
+/// are off by a fraction of a pixel are still sampled.
+const TOLERANCE: f32 = 1e-4;
+
+/// A transformation that moves whole pixels: one of the 8 rotations by multiples of 90° and
+/// reflections, scaled by whole numbers and translated by whole pixels.
+///
+/// Such transformations are rendered by copying pixels, without any floating point math.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Exact {
+    /// Whether the X axis of the output image comes from the Y axis of the input image and the
+    /// other way around
+    transpose: bool,
+
+    /// How much the output X and Y axes are stretched, negative if they are reflected
+    scale: (i64, i64),
+
+    translation: (i64, i64),
+}
+
+/// The closest input pixel to (out - translation) / scale. Halves are rounded towards the bottom
+/// right of the input image like with the nearest filter, so down along Y.
+fn closest(out: usize, translation: i64, scale: i64, along_y: bool) -> i64 {
+    let (n, k) = (scale.signum() * (out as i64 - translation), scale.abs());
+    if along_y {
+        -(k - 2 * n).div_euclid(2 * k)
+    } else {
+        (2 * n + k).div_euclid(2 * k)
+    }
+}
+
+/// Rounds the value if it's a whole number (within the tolerance)
+fn whole(value: f32) -> Option<i64> {
+    let rounded = value.round();
+    ((value - rounded).abs() <= TOLERANCE).then_some(rounded as i64)
+}
+
+impl Exact {
+    /// Recognizes a homogeneous 3x3 `matrix` that moves whole pixels
+    pub(crate) fn new(matrix: &Array2<f32>) -> Option<Self> {
+        let m = |row: usize, col: usize| whole(matrix[[row, col]]);
+
+        if (m(2, 0)?, m(2, 1)?, m(2, 2)?) != (0, 0, 1) {
+            return None;
+        }
+
+        let translation = (m(0, 2)?, m(1, 2)?);
+        let (transpose, scale) = match (m(0, 0)?, m(0, 1)?, m(1, 0)?, m(1, 1)?) {
+            (x, 0, 0, y) if x != 0 && y != 0 => (false, (x, y)),
+            (0, x, y, 0) if x != 0 && y != 0 => (true, (x, y)),
+            _ => return None,
+        };
+
+        Some(Exact {
+            transpose,
+            scale,
+            translation,
+        })
+    }
+
+    /// Whether copying the pixels gives the same image as sampling the input with `sampling`.
+    ///
+    /// Interpolating filters read exactly the input pixel at its center, so they keep the pixels
+    /// as long as the image isn't enlarged. Enlarged images are only made of whole pixels with
+    /// the nearest filter.
+    pub(crate) fn preserves<T>(&self, sampling: &Sampling<T>) -> bool {
+        let enlarged = self.scale.0.abs() > 1 || self.scale.1.abs() > 1;
+
+        sampling.antialias == Antialias::None
+            && match sampling.filter {
+                Filter::Nearest => true,
+                Filter::Bilinear | Filter::Bicubic | Filter::Lanczos3 => !enlarged,
+                Filter::Mitchell => false,
+            }
+    }
+
+    /// Finds which pixels copying a `width`x`height` input image into an
+    /// `out_width`x`out_height` output image reads. Only whole input pixels are read, so it's
+    /// never more than the edges, whatever the reach of the filter.
+    pub(crate) fn coverage(
+        &self,
+        (width, height): (usize, usize),
+        (out_width, out_height): (usize, usize),
+    ) -> Coverage {
+        let (cols, rows) = if self.transpose {
+            (height, width)
+        } else {
+            (width, height)
+        };
+        let (scale_x, scale_y) = self.scale;
+        let (translation_x, translation_y) = self.translation;
+
+        // The pixels are moved in order, so the corners of the output image read the outermost
+        // input pixels
+        let inside = |out: usize, translation: i64, scale: i64, along_y: bool, size: usize| {
+            [0, out - 1]
+                .into_iter()
+                .map(|out| closest(out, translation, scale, along_y))
+                .all(|pixel| (0..size as i64).contains(&pixel))
+        };
+
+        if inside(out_width, translation_x, scale_x, self.transpose, cols)
+            && inside(out_height, translation_y, scale_y, !self.transpose, rows)
+        {
+            Coverage::Inside
+        } else {
+            Coverage::Edges
+        }
+    }
+
+    /// Composites the input pixel every output pixel is moved from over it, reading the input
+    /// according to the border of `sampling` outside of it.
+    ///
+    /// The pixel is the closest one to where the output pixel lands in the input, like with the
+    /// nearest filter, only computed exactly. Opaque pixels are copied as they are.
+    pub(crate) fn render<T: Sample>(
+        &self,
+        input: &ImageArray<T>,
+        output: &mut ImageArray<T>,
+        sampling: &Sampling<T>,
+    ) {
+        let border = &sampling.border;
+        let (out_height, out_width, out_channels) = output.dim();
+
+        // Turn the input so that its rows run along the output rows, with Y pointing up
+        let mut view = input.view();
+        view.invert_axis(Axis(0));
+        if self.transpose {
+            view.swap_axes(0, 1);
+        }
+        let (rows, cols, channels) = view.dim();
+
+        let (scale_x, scale_y) = self.scale;
+        let (translation_x, translation_y) = self.translation;
+
+        let columns: Vec<Option<usize>> = (0..out_width)
+            .map(|x| {
+                let column = closest(x, translation_x, scale_x, self.transpose) as isize;
+                border.index(column, cols)
+            })
+            .collect();
+
+        output
+            .axis_iter_mut(Axis(0))
+            .into_par_iter()
+            .enumerate()
+            .for_each(|(y, mut out_row)| {
+                let row = closest(out_height - y - 1, translation_y, scale_y, !self.transpose);
+                let row = row as isize;
+                let row = border.index(row, rows);
+
+                for (x, column) in columns.iter().enumerate() {
+                    let pixel = match (row, *column) {
+                        (Some(row), Some(column)) => {
+                            images::to_rgba(channels, |channel| view[[row, column, channel]])
+                        }
+                        _ => border.color,
+                    };
+
+                    let below = images::to_rgba(out_channels, |channel| out_row[[x, channel]]);
+                    let pixel = sampling
+                        .compositing
+                        .composite(pixel, below, sampling.linear);
+
+                    let pixel = images::from_rgba(pixel, out_channels);
+                    for (channel, value) in pixel.into_iter().take(out_channels).enumerate() {
+                        out_row[[x, channel]] = value;
+                    }
+                }
+            });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+
+    use super::*;
+    use crate::{
+        matrix_ext::MatrixExt,
+        render::tests::{gathered, pattern, sampling},
+        transform,
+    };
+
+    /// Renders a 40x23 image through `matrix` into a `width`x23 image, copying pixels if the
+    /// matrix is recognized like when rendering, otherwise always sampling it
+    fn rendered(matrix: &Array2<f32>, width: usize, copy: bool) -> ImageArray<u8> {
+        let input = pattern((40, 23), 3);
+        let sampling = sampling(Filter::Bilinear);
+
+        match Exact::new(matrix).filter(|exact| copy && exact.preserves(&sampling)) {
+            Some(exact) => {
+                let mut output = images::filled((width, 23), 4, [0; 4]);
+                exact.render(&input, &mut output, &sampling);
+                output
+            }
+            None => {
+                let mut inverse = matrix.clone();
+                inverse.invert().unwrap();
+                gathered(&input, (width, 23), &inverse, &sampling)
+            }
+        }
+    }
+
+    #[test]
+    fn copies_like_sampling_for_large_offsets() {
+        for (x, whole) in [
+            (20000.0, true),
+            (20000.1, false),
+            (50000.5, false),
+            (7.5, false),
+        ] {
+            let matrix = transform::translation(x, 0.0);
+            assert_eq!(Exact::new(&matrix).is_some(), whole, "{x}");
+
+            let width = x as usize + 40;
+            assert_eq!(
+                rendered(&matrix, width, true),
+                rendered(&matrix, width, false),
+                "{x}"
+            );
+        }
+    }
+
+    #[test]
+    fn recognizes_rotations_around_the_center() {
+        let matrix = transform::around(&transform::rotation(PI / 2.0), (19.5, 11.5));
+        let exact = Exact::new(&matrix).unwrap();
+        assert!(exact.transpose);
+
+        assert_eq!(rendered(&matrix, 40, true), rendered(&matrix, 40, false));
+    }
+
+    #[test]
+    fn covers_only_the_pixels_it_copies() {
+        let input = pattern::<u8>((40, 23), 3);
+        let sampling = sampling(Filter::Bicubic);
+
+        // Copies into an output image of the given size, and checks that it's only covered
+        // where the copy leaves no transparent pixels
+        let covers = |matrix: &Array2<f32>, out_dims: (usize, usize)| {
+            let exact = Exact::new(matrix).unwrap();
+            let mut output = images::filled(out_dims, 4, [0; 4]);
+            exact.render(&input, &mut output, &sampling);
+            let opaque = output
+                .lanes(Axis(2))
+                .into_iter()
+                .all(|pixel| pixel[3] == 255);
+
+            let coverage = exact.coverage((40, 23), out_dims);
+            assert_eq!(
+                coverage == Coverage::Inside,
+                opaque,
+                "{matrix}, {out_dims:?}"
+            );
+            coverage
+        };
+
+        // The bicubic filter reaches beyond the edges, but only reads the pixels at its center
+        let turned = transform::around(&transform::rotation(PI), (19.5, 11.0));
+        let mut inverse = turned.clone();
+        inverse.invert().unwrap();
+        let reached = Coverage::find(&inverse, (40, 23), (40, 23), &sampling);
+        assert_eq!(reached, Coverage::Edges);
+        assert!(Exact::new(&turned).unwrap().preserves(&sampling));
+
+        assert_eq!(covers(&turned, (40, 23)), Coverage::Inside);
+        assert_eq!(covers(&turned, (41, 23)), Coverage::Edges);
+
+        let turned = transform::around(&transform::rotation(PI / 2.0), (19.0, 11.0));
+        assert_eq!(covers(&turned, (40, 23)), Coverage::Edges);
+
+        let turned = transform::translation(-8.0, 8.0).dot(&turned);
+        assert_eq!(covers(&turned, (23, 40)), Coverage::Inside);
+        assert_eq!(covers(&turned, (23, 41)), Coverage::Edges);
+
+        let enlarged = transform::scale(2.0, 3.0);
+        assert_eq!(covers(&enlarged, (79, 68)), Coverage::Inside);
+        assert_eq!(covers(&enlarged, (80, 68)), Coverage::Edges);
+        assert_eq!(covers(&enlarged, (79, 69)), Coverage::Edges);
+    }
+}
@@ -0,0 +1,557 @@
+use ndarray::Array2;
+use owo_colors::OwoColorize as _;
+
+/// Values closer than this to the identity are not worth mentioning
+const EPSILON: f64 = 1e-6;
+
+/// The linear part of a transformation, split into the named transformations that make it up.
+///
+/// Applied in order: reflect across the X axis, scale, shear X, rotate (like the named options).
+struct Decomposition {
+    reflect: bool,
+    scale: (f64, f64),
+    shear: f64,
+    angle: f64,
+}
+
+impl Decomposition {
+    /// Decomposes `[[a, b], [c, d]]` with a QR decomposition into a rotation and an upper
+    /// triangular matrix holding the scale and shear. Returns [`None`] for singular matrices.
+    fn new([[a, b], [c, d]]: [[f64; 2]; 2]) -> Option<Self> {
+        let det = a * d - b * c;
+        let scale_x = a.hypot(c);
+        if scale_x == 0.0 || det == 0.0 {
+            return None;
+        }
+
+        // The first column is the rotated and scaled X axis
+        let angle = c.atan2(a);
+
+        // R(-angle) * A = [[scale_x, m], [0, scale_y]]
+        let m = (a * b + c * d) / scale_x;
+        let scale_y = det / scale_x;
+
+        Some(Decomposition {
+            reflect: det < 0.0,
+            scale: (scale_x, scale_y.abs()),
+            shear: m / scale_y,
+            angle,
+        })
+    }
+
+    /// The decomposition followed by the `translation` as a pipeline (for `--transform`)
+    fn pipeline(&self, (x, y): (f64, f64)) -> Vec<String> {
+        let mut steps = Vec::new();
+
+        if self.reflect {
+            steps.push("reflect-x".to_string());
+        }
+        if !is_one(self.scale.0) || !is_one(self.scale.1) {
+            steps.push(format!(
+                "scale {} {}",
+                number(self.scale.0),
+                number(self.scale.1)
+            ));
+        }
+        if !is_zero(self.shear) {
+            steps.push(format!("shear-x {}", number(self.shear)));
+        }
+        if !is_zero(self.angle) {
+            steps.push(format!("rotate {}deg", number(self.angle.to_degrees())));
+        }
+        if !is_zero(x) || !is_zero(y) {
+            steps.push(format!("translate {} {}", number(x), number(y)));
+        }
+
+        steps
+    }
+
+    /// What the decomposition does, in words
+    fn describe(&self) -> Vec<String> {
+        let mut phrases = Vec::new();
+
+        if self.reflect {
+            phrases.push("flips orientation (mirrors across the X axis)".to_string());
+        }
+
+        let (x, y) = self.scale;
+        if (x - y).abs() <= EPSILON * x.max(y) {
+            if !is_one(x) {
+                phrases.push(format!("{} uniformly by {}", stretch_verb(x), number(x)));
+            }
+        } else {
+            for (axis, factor) in [("X", x), ("Y", y)] {
+                if !is_one(factor) {
+                    phrases.push(format!(
+                        "{} {axis} by {}",
+                        stretch_verb(factor),
+                        number(factor)
+                    ));
+                }
+            }
+        }
+
+        if !is_zero(self.shear) {
+            phrases.push(format!("shears X by {} times Y", number(self.shear)));
+        }
+
+        if !is_zero(self.angle) {
+            let degrees = self.angle.to_degrees();
+            let direction = if degrees > 0.0 {
+                "counter-clockwise"
+            } else {
+                "clockwise"
+            };
+            phrases.push(format!("rotates {}° {direction}", number(degrees.abs())));
+        }
+
+        phrases
+    }
+}
+
+/// The singular values of a 2x2 matrix, from its decomposition `A = R(φ) * diag(values) * R(θ)`
+struct Svd {
+    values: (f64, f64),
+
+    /// The direction (in radians) of the input that is stretched the most
+    direction: f64,
+}
+
+impl Svd {
+    fn new([[a, b], [c, d]]: [[f64; 2]; 2]) -> Self {
+        let (e, f) = ((a + d) / 2.0, (a - d) / 2.0);
+        let (g, h) = ((c + b) / 2.0, (c - b) / 2.0);
+        let (q, r) = (e.hypot(h), f.hypot(g));
+        let (a1, a2) = (g.atan2(f), h.atan2(e));
+
+        Svd {
+            values: (q + r, (q - r).abs()),
+            direction: -(a2 - a1) / 2.0,
+        }
+    }
+
+    fn condition(&self) -> f64 {
+        self.values.0 / self.values.1
+    }
+}
+
+/// The largest and smallest factor a 2x2 matrix stretches vectors by
+pub(crate) fn singular_values(matrix: [[f64; 2]; 2]) -> (f64, f64) {
+    Svd::new(matrix).values
+}
+
+/// The eigenvalues of a 2x2 matrix along with their eigenvectors, or the complex pair
+enum Eigen {
+    Real([(f64, Option<[f64; 2]>); 2]),
+    Complex { real: f64, imaginary: f64 },
+}
+
+impl Eigen {
+    fn new([[a, b], [c, d]]: [[f64; 2]; 2]) -> Self {
+        let half_trace = (a + d) / 2.0;
+        let discriminant = half_trace * half_trace - (a * d - b * c);
+
+        if discriminant < 0.0 {
+            return Eigen::Complex {
+                real: half_trace,
+                imaginary: (-discriminant).sqrt(),
+            };
+        }
+
+        // Diagonal matrices scale along the axes, even when both eigenvalues are the same
+        if is_zero(b) && is_zero(c) {
+            return Eigen::Real([(a, Some([1.0, 0.0])), (d, Some([0.0, 1.0]))]);
+        }
+
+        let root = discriminant.sqrt();
+        let vector = |lambda: f64| {
+            let [x, y] = if b.abs() >= c.abs() {
+                [b, lambda - a]
+            } else {
+                [lambda - d, c]
+            };
+
+            // Point the vector right (or up) so it reads the same regardless of rounding
+            let length = x.hypot(y).copysign(if x.abs() > EPSILON { x } else { y });
+            (length.abs() > EPSILON).then(|| [x / length + 0.0, y / length + 0.0])
+        };
+
+        Eigen::Real([
+            (half_trace + root, vector(half_trace + root)),
+            (half_trace - root, vector(half_trace - root)),
+        ])
+    }
+}
+
+fn is_zero(value: f64) -> bool {
+    value.abs() <= EPSILON
+}
+
+fn is_one(value: f64) -> bool {
+    is_zero(value - 1.0)
+}
+
+fn stretch_verb(factor: f64) -> &'static str {
+    if factor > 1.0 {
+        "stretches"
+    } else {
+        "shrinks"
+    }
+}
+
+/// Normalizes the angle of an axis (which has no direction) into [0°, 180°)
+fn axis(degrees: f64) -> f64 {
+    let degrees = degrees.rem_euclid(180.0);
+
+    // Rounding errors just below 180° belong to 0°
+    if 180.0 - degrees < EPSILON {
+        0.0
+    } else {
+        degrees
+    }
+}
+
+/// Formats a number with up to 4 decimals, without trailing zeros
+fn number(value: f64) -> String {
+    let formatted = format!("{value:.4}");
+    let formatted = formatted.trim_end_matches('0').trim_end_matches('.');
+
+    match formatted {
+        "-0" => "0".to_string(),
+        _ => formatted.to_string(),
+    }
+}
+
+/// Joins the phrases into a sentence, like "a, b and c"
+fn sentence(phrases: &[String]) -> String {
+    let text = match phrases {
+        [] => return "Does nothing.".to_string(),
+        [phrase] => phrase.clone(),
+        [rest @ .., last] => format!("{} and {last}", rest.join(", ")),
+    };
+
+    let mut chars = text.chars();
+    let first = chars.next().unwrap().to_uppercase();
+    format!("{first}{}.", chars.as_str())
+}
+
+/// Prints what the transformation does: its decomposition into named transformations, singular
+/// values, eigenvalues, trace and condition number, and a description in words
+pub(crate) fn explain(matrix: &Array2<f32>) {
+    let mut m = matrix.mapv(|v| v as f64);
+
+    // A homogeneous matrix can be scaled as a whole without changing what it does
+    let w = m[[2, 2]];
+    if m[[2, 0]] == 0.0 && m[[2, 1]] == 0.0 && w != 0.0 {
+        m /= w;
+    }
+    let projective = m[[2, 0]] != 0.0 || m[[2, 1]] != 0.0 || m[[2, 2]] != 1.0;
+
+    let linear = [[m[[0, 0]], m[[0, 1]]], [m[[1, 0]], m[[1, 1]]]];
+    let translation = (m[[0, 2]], m[[1, 2]]);
+
+    println!("{}", "Explanation:".blue());
+    if projective {
+        println!(
+            "  {}",
+            "(of the linear part at the origin, the perspective row is described separately)"
+                .dimmed()
+        );
+    }
+
+    let decomposition = Decomposition::new(linear);
+    let svd = Svd::new(linear);
+    let eigen = Eigen::new(linear);
+
+    let field = |name: &str, value: String| println!("  {} {}", name.blue(), value.yellow());
+
+    match &decomposition {
+        Some(decomposition) => {
+            field(
+                "Rotation:",
+                format!("{}°", number(decomposition.angle.to_degrees())),
+            );
+            field(
+                "Scale:",
+                format!(
+                    "({}, {})",
+                    number(decomposition.scale.0),
+                    number(decomposition.scale.1)
+                ),
+            );
+            field("Shear:", number(decomposition.shear));
+            field(
+                "Reflection:",
+                if decomposition.reflect {
+                    "across the X axis".to_string()
+                } else {
+                    "none".to_string()
+                },
+            );
+        }
+        None => field("Decomposition:", "none, the matrix is singular".to_string()),
+    }
+
+    if !projective {
+        field(
+            "Translation:",
+            format!("({}, {})", number(translation.0), number(translation.1)),
+        );
+    }
+
+    field(
+        "Singular values:",
+        format!(
+            "{}, {} (stretching the input most along {}° and least along {}°)",
+            number(svd.values.0),
+            number(svd.values.1),
+            number(axis(svd.direction.to_degrees())),
+            number(axis(svd.direction.to_degrees() + 90.0))
+        ),
+    );
+
+    match eigen {
+        Eigen::Real(pairs) => {
+            let pairs: Vec<String> = pairs
+                .iter()
+                .map(|(value, vector)| match vector {
+                    Some([x, y]) => {
+                        format!("{} along ({}, {})", number(*value), number(*x), number(*y))
+                    }
+                    None => number(*value),
+                })
+                .collect();
+            field("Eigenvalues:", pairs.join(", "));
+        }
+        Eigen::Complex { real, imaginary } => field(
+            "Eigenvalues:",
+            format!(
+                "{} ± {}i (no real eigenvectors, every direction is rotated)",
+                number(real),
+                number(imaginary)
+            ),
+        ),
+    }
+
+    field("Trace:", number(linear[0][0] + linear[1][1]));
+    field("Condition number:", number(svd.condition()));
+
+    let mut phrases = match &decomposition {
+        Some(decomposition) => decomposition.describe(),
+        None if svd.values.0 <= EPSILON => {
+            vec!["collapses the image onto a single point".to_string()]
+        }
+        None => vec!["collapses the image onto a line".to_string()],
+    };
+
+    if !projective && (!is_zero(translation.0) || !is_zero(translation.1)) {
+        phrases.push(format!(
+            "moves it by ({}, {})",
+            number(translation.0),
+            number(translation.1)
+        ));
+    }
+
+    if projective {
+        phrases.push(format!(
+            "applies a perspective projection (W = {}X + {}Y + {})",
+            number(m[[2, 0]]),
+            number(m[[2, 1]]),
+            number(m[[2, 2]])
+        ));
+    }
+
+    field("In words:", sentence(&phrases));
+
+    if let (Some(decomposition), false) = (&decomposition, projective) {
+        let steps = decomposition.pipeline(translation);
+        if !steps.is_empty() {
+            field("As a pipeline:", format!("\"{}\"", steps.join("; ")));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+
+    use ndarray::array;
+
+    use super::*;
+    use crate::{pipeline, transform};
+
+    /// Matrices that rotate, reflect, shear, collapse and project
+    fn matrices() -> Vec<Array2<f32>> {
+        vec![
+            transform::rotation(PI / 6.0),
+            transform::rotation(-PI / 2.0).dot(&transform::translation(3.0, -4.5)),
+            transform::reflection(true, false).dot(&transform::rotation(0.7)),
+            transform::reflection(false, true),
+            transform::shear(0.5, 0.0).dot(&transform::scale(2.0, 0.5)),
+            transform::shear(0.3, -0.2).dot(&transform::scale(1.5, 3.0)),
+            array![[1.0, 2.0, 0.0], [2.0, 4.0, 0.0], [0.0, 0.0, 1.0]],
+            array![[0.0, 0.0, 5.0], [0.0, 0.0, 0.0], [0.0, 0.0, 1.0]],
+            array![[1.0, 0.2, 3.0], [-0.4, 1.1, 0.0], [0.001, 0.002, 1.0]],
+        ]
+    }
+
+    /// The linear part of the matrix and its translation, as [`explain`] sees them
+    fn parts(matrix: &Array2<f32>) -> ([[f64; 2]; 2], (f64, f64)) {
+        let m = matrix.mapv(|v| v as f64);
+        (
+            [[m[[0, 0]], m[[0, 1]]], [m[[1, 0]], m[[1, 1]]]],
+            (m[[0, 2]], m[[1, 2]]),
+        )
+    }
+
+    fn product(a: [[f64; 2]; 2], b: [[f64; 2]; 2]) -> [[f64; 2]; 2] {
+        std::array::from_fn(|row| {
+            std::array::from_fn(|col| a[row][0] * b[0][col] + a[row][1] * b[1][col])
+        })
+    }
+
+    fn rotation(angle: f64) -> [[f64; 2]; 2] {
+        let (sin, cos) = angle.sin_cos();
+        [[cos, -sin], [sin, cos]]
+    }
+
+    fn assert_close(actual: [[f64; 2]; 2], expected: [[f64; 2]; 2]) {
+        for (actual, expected) in actual.iter().flatten().zip(expected.iter().flatten()) {
+            assert!(
+                (actual - expected).abs() < 1e-9,
+                "{actual:?} != {expected:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn recomposes_the_decomposition() {
+        for matrix in matrices() {
+            let (linear, _) = parts(&matrix);
+            let Some(decomposition) = Decomposition::new(linear) else {
+                let [[a, b], [c, d]] = linear;
+                assert_eq!(a * d - b * c, 0.0, "{matrix}");
+                continue;
+            };
+
+            // Reflect, scale, shear and rotate, applied from the right
+            let (x, y) = decomposition.scale;
+            let reflect = if decomposition.reflect { -1.0 } else { 1.0 };
+            let recomposed = [
+                rotation(decomposition.angle),
+                [[1.0, decomposition.shear], [0.0, 1.0]],
+                [[x, 0.0], [0.0, y]],
+                [[1.0, 0.0], [0.0, reflect]],
+            ]
+            .into_iter()
+            .reduce(product)
+            .unwrap();
+
+            assert_close(recomposed, linear);
+            assert!(x > 0.0 && y > 0.0, "{matrix}");
+        }
+    }
+
+    #[test]
+    fn recomposes_the_singular_value_decomposition() {
+        for matrix in matrices() {
+            let (linear, _) = parts(&matrix);
+            let svd = Svd::new(linear);
+            let (largest, smallest) = svd.values;
+            assert!(largest >= smallest && smallest >= 0.0, "{matrix}");
+
+            // The input directions stretched the most and the least are perpendicular, and so
+            // are the output directions they're stretched into
+            let apply = |[[a, b], [c, d]]: [[f64; 2]; 2], angle: f64| {
+                let (sin, cos) = angle.sin_cos();
+                (a * cos + b * sin, c * cos + d * sin)
+            };
+            let most = apply(linear, svd.direction);
+            let least = apply(linear, svd.direction + std::f64::consts::FRAC_PI_2);
+            assert!((most.0.hypot(most.1) - largest).abs() < 1e-9, "{matrix}");
+            assert!((least.0.hypot(least.1) - smallest).abs() < 1e-9, "{matrix}");
+            assert!(
+                (most.0 * least.0 + most.1 * least.1).abs() < 1e-9,
+                "{matrix}"
+            );
+
+            // A = U * diag(values) * V^T, with the output directions in U and the input ones in V
+            if smallest > 1e-9 {
+                let u = [
+                    [most.0 / largest, least.0 / smallest],
+                    [most.1 / largest, least.1 / smallest],
+                ];
+                let v = rotation(svd.direction);
+                let v_t = [[v[0][0], v[1][0]], [v[0][1], v[1][1]]];
+                let recomposed = product(product(u, [[largest, 0.0], [0.0, smallest]]), v_t);
+
+                assert_close(recomposed, linear);
+            }
+        }
+    }
+
+    #[test]
+    fn recomposes_the_eigendecomposition() {
+        for matrix in matrices() {
+            let (linear, _) = parts(&matrix);
+            let [[a, b], [c, d]] = linear;
+
+            match Eigen::new(linear) {
+                Eigen::Real(pairs) => {
+                    for (value, vector) in pairs {
+                        let Some([x, y]) = vector else { continue };
+                        let (mapped_x, mapped_y) = (a * x + b * y, c * x + d * y);
+
+                        assert!((mapped_x - value * x).abs() < 1e-9, "{matrix}");
+                        assert!((mapped_y - value * y).abs() < 1e-9, "{matrix}");
+                    }
+
+                    // A = V * diag(values) * V^-1 when the eigenvectors span the plane
+                    if let [(first, Some([x1, y1])), (second, Some([x2, y2]))] = pairs {
+                        let det = x1 * y2 - x2 * y1;
+                        if det.abs() > 1e-9 {
+                            let v = [[x1, x2], [y1, y2]];
+                            let inverse = [[y2 / det, -x2 / det], [-y1 / det, x1 / det]];
+                            let recomposed =
+                                product(product(v, [[first, 0.0], [0.0, second]]), inverse);
+
+                            assert_close(recomposed, linear);
+                        }
+                    }
+
+                    let [(first, _), (second, _)] = pairs;
+                    assert!((first + second - (a + d)).abs() < 1e-9, "{matrix}");
+                    assert!((first * second - (a * d - b * c)).abs() < 1e-9, "{matrix}");
+                }
+                Eigen::Complex { real, imaginary } => {
+                    // The pair multiplies to the determinant and adds up to the trace
+                    assert!((2.0 * real - (a + d)).abs() < 1e-9, "{matrix}");
+                    let det = real * real + imaginary * imaginary;
+                    assert!((det - (a * d - b * c)).abs() < 1e-9, "{matrix}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn prints_a_pipeline_that_parses_back() {
+        for matrix in matrices() {
+            let (linear, translation) = parts(&matrix);
+            let projective = matrix[[2, 0]] != 0.0 || matrix[[2, 1]] != 0.0;
+            let Some(decomposition) = Decomposition::new(linear).filter(|_| !projective) else {
+                continue;
+            };
+
+            let source = decomposition.pipeline(translation).join("; ");
+            let parsed = pipeline::parse(&source).unwrap().matrix((10, 10));
+
+            // The numbers are printed with 4 decimals
+            for (parsed, expected) in parsed.iter().zip(&matrix) {
+                assert!(
+                    (parsed - expected).abs() < 1e-3,
+                    "{source}: {parsed} != {expected}"
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,490 @@
+use std::{f64::consts, fmt, ops::Range};
+
+/// An error in an expression, pointing at the offending part of it
+#[derive(Debug)]
+pub(crate) struct ExprError {
+    pub(crate) message: String,
+    pub(crate) span: Range<usize>,
+}
+
+impl ExprError {
+    /// Formats the error along with the expression and a marker under the offending part
+    pub(crate) fn display(&self, source: &str) -> String {
+        let column = source[..self.span.start].chars().count();
+        let width = source[self.span.clone()].chars().count().max(1);
+
+        format!(
+            "{}\n  {}\n  {}{}",
+            self.message,
+            source,
+            " ".repeat(column),
+            "^".repeat(width)
+        )
+    }
+}
+
+impl fmt::Display for ExprError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(f64),
+    Ident(String),
+    Op(char),
+    Open,
+    Close,
+    Comma,
+    End,
+}
+
+fn tokenize(source: &str) -> Result<Vec<(Token, Range<usize>)>, ExprError> {
+    let mut tokens = Vec::new();
+    let mut chars = source.char_indices().peekable();
+
+    while let Some(&(start, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+
+        let token = if c.is_ascii_digit() || c == '.' {
+            let mut end = start;
+            let mut previous = ' ';
+            while let Some(&(i, c)) = chars.peek() {
+                let exponent_sign = (c == '-' || c == '+') && matches!(previous, 'e' | 'E');
+                let exponent = (c == 'e' || c == 'E')
+                    && source[i + 1..]
+                        .trim_start_matches(['-', '+'])
+                        .starts_with(|c: char| c.is_ascii_digit());
+
+                if !(c.is_ascii_digit() || c == '.' || exponent || exponent_sign) {
+                    break;
+                }
+
+                previous = c;
+                end = i + c.len_utf8();
+                chars.next();
+            }
+
+            let text = &source[start..end];
+            Token::Number(text.parse().map_err(|_| ExprError {
+                message: format!("Invalid number `{text}`"),
+                span: start..end,
+            })?)
+        } else if c.is_alphabetic() || c == '_' || c == '°' {
+            let mut end = start;
+            while let Some(&(i, c)) = chars.peek() {
+                if !(c.is_alphanumeric() || c == '_' || c == '°') {
+                    break;
+                }
+                end = i + c.len_utf8();
+                chars.next();
+            }
+
+            Token::Ident(source[start..end].to_string())
+        } else {
+            chars.next();
+            match c {
+                '+' | '-' | '*' | '/' | '^' => Token::Op(c),
+                '(' => Token::Open,
+                ')' => Token::Close,
+                ',' => Token::Comma,
+                _ => {
+                    return Err(ExprError {
+                        message: format!("Unexpected character `{c}`"),
+                        span: start..start + c.len_utf8(),
+                    })
+                }
+            }
+        };
+
+        let end = chars.peek().map_or(source.len(), |&(i, _)| i);
+        tokens.push((token, start..end));
+    }
+
+    tokens.push((Token::End, source.len()..source.len()));
+
+    Ok(tokens)
+}
+
+/// A recursive descent parser evaluating the expression as it goes
+struct Parser {
+    tokens: Vec<(Token, Range<usize>)>,
+    position: usize,
+
+    /// Whether an angle unit (`deg` or `rad`) was used anywhere in the expression
+    has_unit: bool,
+}
+
+impl Parser {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.position].0
+    }
+
+    fn span(&self) -> Range<usize> {
+        self.tokens[self.position].1.clone()
+    }
+
+    fn next(&mut self) -> (Token, Range<usize>) {
+        let token = self.tokens[self.position].clone();
+        if token.0 != Token::End {
+            self.position += 1;
+        }
+        token
+    }
+
+    fn error<T>(&self, message: impl Into<String>) -> Result<T, ExprError> {
+        Err(ExprError {
+            message: message.into(),
+            span: self.span(),
+        })
+    }
+
+    fn expect(&mut self, expected: Token, name: &str) -> Result<(), ExprError> {
+        if *self.peek() == expected {
+            self.next();
+            Ok(())
+        } else {
+            self.error(format!("Expected {name}"))
+        }
+    }
+
+    /// expr := term (('+' | '-') term)*
+    fn expr(&mut self) -> Result<f64, ExprError> {
+        let mut value = self.term()?;
+
+        while let Token::Op(op @ ('+' | '-')) = *self.peek() {
+            self.next();
+            let rhs = self.term()?;
+            value = if op == '+' { value + rhs } else { value - rhs };
+        }
+
+        Ok(value)
+    }
+
+    /// term := unary (('*' | '/') unary)*
+    fn term(&mut self) -> Result<f64, ExprError> {
+        let mut value = self.unary()?;
+
+        while let Token::Op(op @ ('*' | '/')) = *self.peek() {
+            self.next();
+            let rhs = self.unary()?;
+            value = if op == '*' { value * rhs } else { value / rhs };
+        }
+
+        Ok(value)
+    }
+
+    /// unary := ('-' | '+') unary | power
+    fn unary(&mut self) -> Result<f64, ExprError> {
+        match *self.peek() {
+            Token::Op('-') => {
+                self.next();
+                Ok(-self.unary()?)
+            }
+            Token::Op('+') => {
+                self.next();
+                self.unary()
+            }
+            _ => self.power(),
+        }
+    }
+
+    /// power := postfix ('^' unary)?
+    fn power(&mut self) -> Result<f64, ExprError> {
+        let base = self.postfix()?;
+
+        if *self.peek() == Token::Op('^') {
+            self.next();
+            return Ok(base.powf(self.unary()?));
+        }
+
+        Ok(base)
+    }
+
+    /// postfix := primary ('deg' | '°' | 'rad')?
+    fn postfix(&mut self) -> Result<f64, ExprError> {
+        let value = self.primary()?;
+
+        if let Token::Ident(unit) = self.peek() {
+            let factor = match unit.as_str() {
+                "deg" | "°" => consts::PI / 180.0,
+                "rad" => 1.0,
+                _ => return self.error(format!("Unknown unit `{unit}` (expected deg or rad)")),
+            };
+
+            self.next();
+            self.has_unit = true;
+            return Ok(value * factor);
+        }
+
+        Ok(value)
+    }
+
+    /// primary := number | constant | function '(' expr (',' expr)* ')' | '(' expr ')'
+    fn primary(&mut self) -> Result<f64, ExprError> {
+        let (token, span) = self.next();
+
+        match token {
+            Token::Number(value) => Ok(value),
+            Token::Open => {
+                let value = self.expr()?;
+                self.expect(Token::Close, "`)`")?;
+                Ok(value)
+            }
+            Token::Ident(name) if *self.peek() == Token::Open => {
+                self.next();
+
+                let mut args = vec![self.expr()?];
+                while *self.peek() == Token::Comma {
+                    self.next();
+                    args.push(self.expr()?);
+                }
+                self.expect(Token::Close, "`)` or `,`")?;
+
+                call(&name, &args).map_err(|message| ExprError {
+                    message,
+                    span: span.start..self.tokens[self.position - 1].1.end,
+                })
+            }
+            Token::Ident(name) => match name.as_str() {
+                "pi" | "π" => Ok(consts::PI),
+                "tau" | "τ" => Ok(consts::TAU),
+                "e" => Ok(consts::E),
+                _ => Err(ExprError {
+                    message: format!("Unknown constant `{name}` (expected pi, tau or e)"),
+                    span,
+                }),
+            },
+            Token::End => Err(ExprError {
+                message: "Unexpected end of the expression".to_string(),
+                span,
+            }),
+            _ => Err(ExprError {
+                message: "Expected a number, constant, function or `(`".to_string(),
+                span,
+            }),
+        }
+    }
+}
+
+fn call(name: &str, args: &[f64]) -> Result<f64, String> {
+    let unary = |f: fn(f64) -> f64| match args {
+        [x] => Ok(f(*x)),
+        _ => Err(format!("`{name}` takes 1 argument, got {}", args.len())),
+    };
+
+    match name {
+        "sqrt" => unary(f64::sqrt),
+        "cbrt" => unary(f64::cbrt),
+        "abs" => unary(f64::abs),
+        "sin" => unary(f64::sin),
+        "cos" => unary(f64::cos),
+        "tan" => unary(f64::tan),
+        "asin" => unary(f64::asin),
+        "acos" => unary(f64::acos),
+        "atan" => unary(f64::atan),
+        "exp" => unary(f64::exp),
+        "ln" => unary(f64::ln),
+        "log10" => unary(f64::log10),
+        "floor" => unary(f64::floor),
+        "ceil" => unary(f64::ceil),
+        "round" => unary(f64::round),
+        "atan2" => match args {
+            [y, x] => Ok(y.atan2(*x)),
+            _ => Err(format!("`atan2` takes 2 arguments, got {}", args.len())),
+        },
+        _ => Err(format!("Unknown function `{name}`")),
+    }
+}
+
+/// Evaluates the expression, returning its value and whether an angle unit was used in it
+fn evaluate_with_unit(source: &str) -> Result<(f64, bool), ExprError> {
+    let mut parser = Parser {
+        tokens: tokenize(source)?,
+        position: 0,
+        has_unit: false,
+    };
+
+    let value = parser.expr()?;
+
+    if *parser.peek() != Token::End {
+        return parser.error("Expected an operator or the end of the expression");
+    }
+
+    if !value.is_finite() {
+        return Err(ExprError {
+            message: format!("The expression evaluates to {value}"),
+            span: 0..source.len(),
+        });
+    }
+
+    Ok((value, parser.has_unit))
+}
+
+/// Evaluates an arithmetic expression, like `cos(30deg) / 2` or `1/3`.
+///
+/// Angle units (`deg`, `°`, `rad`) convert their operand to radians.
+pub(crate) fn evaluate(source: &str) -> Result<f64, ExprError> {
+    evaluate_with_unit(source).map(|(value, _)| value)
+}
+
+/// Evaluates an angle expression into radians. Without any units, the value is in degrees.
+pub(crate) fn evaluate_angle(source: &str) -> Result<f64, ExprError> {
+    let (value, has_unit) = evaluate_with_unit(source)?;
+
+    Ok(if has_unit { value } else { value.to_radians() })
+}
+
+/// Splits `source` at every `separator` outside of parentheses, returning the parts along with
+/// their positions
+pub(crate) fn split_top_level(source: &str, separator: char) -> Vec<(&str, usize)> {
+    let mut parts = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+
+    for (i, c) in source.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            c if c == separator && depth <= 0 => {
+                parts.push((&source[start..i], start));
+                start = i + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+
+    parts.push((&source[start..], start));
+
+    parts
+}
+
+/// Numeric types that can be the result of an expression
+pub(crate) trait Number: Sized {
+    fn from_f64(value: f64) -> Result<Self, String>;
+}
+
+impl Number for f32 {
+    fn from_f64(value: f64) -> Result<Self, String> {
+        Ok(value as f32)
+    }
+}
+
+impl Number for f64 {
+    fn from_f64(value: f64) -> Result<Self, String> {
+        Ok(value)
+    }
+}
+
+macro_rules! impl_integer {
+    ($($type:ty),*) => {
+        $(
+            impl Number for $type {
+                fn from_f64(value: f64) -> Result<Self, String> {
+                    // Allow for tiny floating point errors, like in `sqrt(2)^2`
+                    let rounded = value.round();
+                    if (value - rounded).abs() > 1e-9 {
+                        return Err(format!("Expected a whole number, got {value}"));
+                    }
+
+                    if rounded < <$type>::MIN as f64 || rounded > <$type>::MAX as f64 {
+                        return Err(format!(
+                            "Expected a number between {} and {}, got {value}",
+                            <$type>::MIN,
+                            <$type>::MAX
+                        ));
+                    }
+
+                    Ok(rounded as $type)
+                }
+            }
+        )*
+    };
+}
+
+impl_integer!(u8, u16, usize, isize);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Evaluates `source`, expecting it to fail, and returns the failing part along with the
+    /// message
+    fn failure(source: &str) -> (&str, String) {
+        let error = evaluate(source).unwrap_err();
+        (&source[error.span.clone()], error.message)
+    }
+
+    #[test]
+    fn follows_operator_precedence() {
+        for (source, expected) in [
+            ("1 + 2 * 3", 7.0),
+            ("(1 + 2) * 3", 9.0),
+            ("1 - 2 - 3", -4.0),
+            ("8 / 4 / 2", 1.0),
+            ("2 * 3 ^ 2", 18.0),
+            ("2 ^ 3 ^ 2", 512.0),
+            ("1 / 3 * 3", 1.0),
+        ] {
+            assert_eq!(evaluate(source).unwrap(), expected, "{source}");
+        }
+    }
+
+    #[test]
+    fn negates_below_powers() {
+        for (source, expected) in [
+            ("-2 ^ 2", -4.0),
+            ("(-2) ^ 2", 4.0),
+            ("2 ^ -1", 0.5),
+            ("2 * -3", -6.0),
+            ("--3", 3.0),
+            ("+-1", -1.0),
+            ("-(1 + 2)", -3.0),
+        ] {
+            assert_eq!(evaluate(source).unwrap(), expected, "{source}");
+        }
+    }
+
+    #[test]
+    fn converts_angle_units() {
+        assert!((evaluate("cos(60deg)").unwrap() - 0.5).abs() < 1e-12);
+        assert_eq!(evaluate("180°").unwrap(), consts::PI);
+        assert_eq!(evaluate_angle("90").unwrap(), consts::FRAC_PI_2);
+        assert_eq!(evaluate_angle("pi/2 rad").unwrap(), consts::FRAC_PI_2);
+        assert_eq!(evaluate("atan2(1, 1)").unwrap(), consts::FRAC_PI_4);
+    }
+
+    #[test]
+    fn points_at_the_offending_part() {
+        for (source, part, message) in [
+            ("1 + foo", "foo", "Unknown constant"),
+            ("2 * (3", "", "Expected `)`"),
+            ("1 2", "2", "Expected an operator"),
+            ("sqrt(1, 2) + 1", "sqrt(1, 2)", "`sqrt` takes 1 argument"),
+            ("30 grad", "grad", "Unknown unit"),
+            ("1 / 0", "1 / 0", "The expression evaluates to inf"),
+        ] {
+            let (found, found_message) = failure(source);
+            assert_eq!(found, part, "{source}");
+            assert!(found_message.starts_with(message), "{found_message}");
+        }
+
+        let error = evaluate("1 + foo").unwrap_err();
+        assert_eq!(
+            error.display("1 + foo"),
+            "Unknown constant `foo` (expected pi, tau or e)\n  1 + foo\n      ^^^"
+        );
+    }
+
+    #[test]
+    fn splits_outside_of_parentheses() {
+        assert_eq!(
+            split_top_level("atan2(1, 2), 3,4", ','),
+            [("atan2(1, 2)", 0), (" 3", 12), ("4", 15)]
+        );
+    }
+}
@@ -0,0 +1,705 @@
+use std::f32::consts::PI;
+
+use clap::ValueEnum;
+
+use crate::images::{self, Image, Sample};
+
+/// The maximum amount of taps a filter can use along one axis
+const MAX_TAPS: usize = 6;
+
+/// How many pixels of a row [`sample_row`] finds the taps of at once
+const LANES: usize = 64;
+
+/// Positions further away than this can't tell pixels apart anymore, and are projected from close
+/// to the horizon of perspective transformations
+const MAX_POSITION: f32 = (1 << 24) as f32;
+
+/// The filter used to sample the input image between pixel centers
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum Filter {
+    /// Use the closest pixel (sharp, blocky edges)
+    #[default]
+    Nearest,
+
+    /// Linearly interpolate between the 2x2 closest pixels
+    Bilinear,
+
+    /// Catmull-Rom cubic spline over the 4x4 closest pixels (sharp)
+    Bicubic,
+
+    /// Mitchell-Netravali cubic over the 4x4 closest pixels (smooth, little ringing)
+    Mitchell,
+
+    /// Windowed sinc over the 6x6 closest pixels (sharpest, may ring)
+    Lanczos3,
+}
+
+/// How to avoid aliasing when the transformation shrinks the image
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum Antialias {
+    /// Take a single sample per output pixel
+    #[default]
+    None,
+
+    /// Average a grid of samples per output pixel (see --samples)
+    Supersample,
+
+    /// Stretch the filter over the elliptical footprint of the output pixel in the input image
+    Footprint,
+}
+
+/// What is read at positions outside of the input image
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Edge {
+    /// Transparent pixels
+    Transparent,
+
+    /// The color given by --background
+    Background,
+
+    /// The closest pixel on the edge of the image
+    Clamp,
+
+    /// The image tiled infinitely
+    Repeat,
+
+    /// The image tiled infinitely, mirroring every other copy so that the tiles are seamless
+    Mirror,
+}
+
+/// How positions outside of the input image are sampled
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Border<T = u8> {
+    pub(crate) edge: Edge,
+
+    /// The RGBA color read outside of the image with the transparent and background edges
+    pub(crate) color: [T; 4],
+}
+
+impl Border {
+    /// Converts the color to another type of samples
+    pub(crate) fn convert<T: Sample>(&self) -> Border<T> {
+        Border {
+            edge: self.edge,
+            color: self.color.map(u8::convert),
+        }
+    }
+}
+
+impl<T: Sample> Border<T> {
+    /// Whether sampling at the position (`x`, `y`) reads from a `width`x`height` image at all
+    pub(crate) fn covers(&self, x: f32, y: f32, (width, height): (usize, usize)) -> bool {
+        match self.edge {
+            Edge::Transparent | Edge::Background => {
+                x >= -0.5 && y >= -0.5 && x < width as f32 - 0.5 && y < height as f32 - 0.5
+            }
+            Edge::Clamp | Edge::Repeat | Edge::Mirror => true,
+        }
+    }
+
+    /// Maps the index of a pixel along an axis of length `len` into the image, or [`None`] if
+    /// the border color should be used instead
+    pub(crate) fn index(&self, i: isize, len: usize) -> Option<usize> {
+        let len = len as isize;
+
+        match self.edge {
+            Edge::Transparent | Edge::Background => (0..len).contains(&i).then_some(i as usize),
+            Edge::Clamp => Some(i.clamp(0, len - 1) as usize),
+            Edge::Repeat => Some(i.rem_euclid(len) as usize),
+            Edge::Mirror => {
+                let i = i.rem_euclid(2 * len);
+                Some(if i < len { i } else { 2 * len - 1 - i } as usize)
+            }
+        }
+    }
+
+    /// Reads the pixel at (`x`, `y`), which may lie outside of the image
+    fn pixel<I: Image<T> + ?Sized>(&self, image: &I, x: isize, y: isize) -> [T; 4] {
+        let (width, height) = image.dimensions();
+
+        match (self.index(x, width), self.index(y, height)) {
+            (Some(x), Some(y)) => image.pixel(x, y),
+            _ => self.color,
+        }
+    }
+}
+
+impl Filter {
+    /// How many input pixels are read for a single sample
+    pub(crate) fn taps(self) -> usize {
+        match self {
+            Filter::Nearest => 1,
+            _ => (2.0 * self.radius()) as usize * (2.0 * self.radius()) as usize,
+        }
+    }
+
+    /// How far from the sampled position (in pixels) the kernel is non-zero
+    pub(crate) fn radius(self) -> f32 {
+        match self {
+            Filter::Nearest => 0.5,
+            Filter::Bilinear => 1.0,
+            Filter::Bicubic | Filter::Mitchell => 2.0,
+            Filter::Lanczos3 => 3.0,
+        }
+    }
+
+    /// The kernel weight of a pixel at a distance of `x` pixels from the sampled position
+    fn weight(self, x: f32) -> f32 {
+        let x = x.abs();
+
+        match self {
+            Filter::Nearest => (x < 0.5) as u8 as f32,
+            Filter::Bilinear => (1.0 - x).max(0.0),
+            Filter::Bicubic => cubic(x, 0.0, 0.5),
+            Filter::Mitchell => cubic(x, 1.0 / 3.0, 1.0 / 3.0),
+            Filter::Lanczos3 => {
+                if x < 3.0 {
+                    sinc(x) * sinc(x / 3.0)
+                } else {
+                    0.0
+                }
+            }
+        }
+    }
+}
+
+/// Converts an RGBA pixel into values in the range of its type that can be blended: in linear
+/// light if `linear`, and with the color premultiplied by alpha, so that transparent pixels don't
+/// bleed their color into their neighbors. Alpha isn't gamma encoded, so it's kept as it is.
+// Called for every tap of the filters, so it's always inlined to keep them fast
+#[inline(always)]
+pub(crate) fn blendable<T: Sample>(pixel: [T; 4], linear: bool) -> [f32; 4] {
+    let [r, g, b, a] = pixel;
+    let [r, g, b] = if linear {
+        [r.to_linear(), g.to_linear(), b.to_linear()]
+    } else {
+        [r.to_f32(), g.to_f32(), b.to_f32()]
+    };
+
+    let a = a.to_f32();
+    let coverage = a * (1.0 / T::MAX);
+
+    [r * coverage, g * coverage, b * coverage, a]
+}
+
+/// Converts blended values back into an RGBA pixel, see [`blendable`]. Pixels without any
+/// coverage are black.
+#[inline(always)]
+pub(crate) fn blended<T: Sample>(values: [f32; 4], linear: bool) -> [T; 4] {
+    let [r, g, b, a] = values;
+
+    let [r, g, b] = if a > 0.0 {
+        let scale = T::MAX / a;
+        [r * scale, g * scale, b * scale]
+    } else {
+        [0.0; 3]
+    };
+
+    if linear {
+        [
+            T::from_linear(r),
+            T::from_linear(g),
+            T::from_linear(b),
+            T::from_f32(a),
+        ]
+    } else {
+        [
+            T::from_f32(r),
+            T::from_f32(g),
+            T::from_f32(b),
+            T::from_f32(a),
+        ]
+    }
+}
+
+/// How the colors of an image are mixed with the colors below it where both are covered
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum Blend {
+    /// The colors of the image cover the ones below it
+    #[default]
+    Normal,
+
+    /// Multiply the colors, which only darkens them (like printing one image over another)
+    Multiply,
+
+    /// Multiply the inverted colors, which only lightens them (like projecting one image onto another)
+    Screen,
+
+    /// Add the colors, which only lightens them
+    Add,
+}
+
+/// How an image is composited over what's below it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Compositing {
+    pub(crate) blend: Blend,
+
+    /// How much the image covers what's below it, from 0 to 1
+    pub(crate) opacity: f32,
+}
+
+impl Default for Compositing {
+    fn default() -> Self {
+        Compositing {
+            blend: Blend::Normal,
+            opacity: 1.0,
+        }
+    }
+}
+
+impl Compositing {
+    /// Whether compositing over transparent pixels keeps the image as it is
+    pub(crate) fn keeps_over_transparent(&self) -> bool {
+        self.opacity == 1.0
+    }
+
+    /// Composites the blended `values` over the blended values `below` them, see [`blendable`]
+    pub(crate) fn over<T: Sample>(&self, values: [f32; 4], below: [f32; 4]) -> [f32; 4] {
+        let values = if self.opacity == 1.0 {
+            values
+        } else {
+            values.map(|value| value * self.opacity)
+        };
+
+        // The colors are premultiplied, so where both are covered the mixed colors are weighted
+        // by both alphas
+        let (alpha, below_alpha) = (values[3] / T::MAX, below[3] / T::MAX);
+        let uncovered = 1.0 - alpha;
+
+        let color = |value: f32, below: f32| match self.blend {
+            Blend::Normal => value + below * uncovered,
+            Blend::Multiply => {
+                value * (1.0 - below_alpha) + value * below / T::MAX + below * uncovered
+            }
+            Blend::Screen => value + below - value * below / T::MAX,
+            Blend::Add => value + below,
+        };
+
+        [
+            color(values[0], below[0]),
+            color(values[1], below[1]),
+            color(values[2], below[2]),
+            values[3] + below[3] * uncovered,
+        ]
+    }
+
+    /// Composites the RGBA `pixel` over the pixel `below` it like [`Compositing::over`], keeping
+    /// pixels that would come out the same as they are
+    pub(crate) fn composite<T: Sample>(
+        &self,
+        pixel: [T; 4],
+        below: [T; 4],
+        linear: bool,
+    ) -> [T; 4] {
+        let covers = self.blend == Blend::Normal && pixel[3] == T::opaque();
+        if self.keeps_over_transparent() && (covers || below[3] == T::default()) {
+            return pixel;
+        }
+
+        blended(
+            self.over::<T>(blendable(pixel, linear), blendable(below, linear)),
+            linear,
+        )
+    }
+}
+
+/// The Mitchell-Netravali family of cubic kernels, parametrized by `b` and `c`
+fn cubic(x: f32, b: f32, c: f32) -> f32 {
+    let x2 = x * x;
+    let x3 = x2 * x;
+
+    if x < 1.0 {
+        ((12.0 - 9.0 * b - 6.0 * c) * x3 + (-18.0 + 12.0 * b + 6.0 * c) * x2 + (6.0 - 2.0 * b))
+            / 6.0
+    } else if x < 2.0 {
+        ((-b - 6.0 * c) * x3
+            + (6.0 * b + 30.0 * c) * x2
+            + (-12.0 * b - 48.0 * c) * x
+            + (8.0 * b + 24.0 * c))
+            / 6.0
+    } else {
+        0.0
+    }
+}
+
+fn sinc(x: f32) -> f32 {
+    if x == 0.0 {
+        1.0
+    } else {
+        let x = x * PI;
+        x.sin() / x
+    }
+}
+
+/// Computes the pixel indices and kernel weights along one axis.
+///
+/// The indices may lie outside of the image, see [`Border`].
+fn taps(filter: Filter, pos: f32) -> ([isize; MAX_TAPS], [f32; MAX_TAPS], usize) {
+    let mut indices = [0; MAX_TAPS];
+    let mut weights = [0.0; MAX_TAPS];
+
+    let radius = filter.radius();
+    let first = (pos - radius).floor() as isize + 1;
+
+    // The kernel covers exactly 2 * radius pixels, rounding both of its ends separately could
+    // add another one
+    let count = ((2.0 * radius) as usize).min(MAX_TAPS);
+
+    for (i, (index, weight)) in indices.iter_mut().zip(&mut weights).enumerate().take(count) {
+        *index = first + i as isize;
+        *weight = filter.weight(pos - *index as f32);
+    }
+
+    (indices, weights, count)
+}
+
+/// Rounds `x` down like [`f32::floor`] in a way the compiler can vectorize, as long as the
+/// position it's computed from is [`samplable`]
+fn floor(x: f32) -> f32 {
+    let truncated = x as i32 as f32;
+    if truncated > x {
+        truncated - 1.0
+    } else {
+        truncated
+    }
+}
+
+/// Whether the position (`x`, `y`) is finite and close enough to be sampled, see [`MAX_POSITION`]
+fn samplable(x: f32, y: f32) -> bool {
+    x.abs() < MAX_POSITION && y.abs() < MAX_POSITION
+}
+
+/// Samples `image` at the position (`x`, `y`), where pixel centers lie on whole numbers and
+/// Y points down like the rows of the array.
+///
+/// Positions and filter taps outside of the image are read according to the `border`, and
+/// positions that aren't finite or are too far away read the color of the border. Returns the
+/// blended values, see [`blendable`].
+pub(crate) fn sample<T: Sample, I: Image<T> + ?Sized>(
+    image: &I,
+    x: f32,
+    y: f32,
+    filter: Filter,
+    border: &Border<T>,
+    linear: bool,
+) -> [f32; 4] {
+    let (width, height) = image.dimensions();
+
+    if !samplable(x, y) || !border.covers(x, y, (width, height)) {
+        return blendable(border.color, linear);
+    }
+
+    if filter == Filter::Nearest {
+        let pixel = border.pixel(
+            image,
+            (x + 0.5).floor() as isize,
+            (y + 0.5).floor() as isize,
+        );
+        return blendable(pixel, linear);
+    }
+
+    let (xs, x_weights, x_count) = taps(filter, x);
+    let (ys, y_weights, y_count) = taps(filter, y);
+
+    let mut sum = [0.0f32; 4];
+    let mut total_weight = 0.0;
+
+    for (&y, &y_weight) in ys.iter().zip(&y_weights).take(y_count) {
+        for (&x, &x_weight) in xs.iter().zip(&x_weights).take(x_count) {
+            let weight = x_weight * y_weight;
+            let pixel = blendable(border.pixel(image, x, y), linear);
+            for (value, channel) in sum.iter_mut().zip(pixel) {
+                *value += channel * weight;
+            }
+            total_weight += weight;
+        }
+    }
+
+    sum.map(|value| value / total_weight)
+}
+
+/// Samples `image` at the positions (`xs`, `ys`) of a row of output pixels like [`sample`],
+/// storing the blended values in `values`.
+///
+/// With the nearest and bilinear filters, images stored contiguously are sampled a chunk of the
+/// row at a time: the taps and weights of all of its pixels are found first in loops the compiler
+/// vectorizes, and the pixels whose taps all lie inside the image are then read straight from the
+/// samples, giving the same values as [`sample`]. Other pixels and filters are sampled one by one.
+pub(crate) fn sample_row<T: Sample, I: Image<T> + ?Sized>(
+    image: &I,
+    (xs, ys): (&[f32], &[f32]),
+    filter: Filter,
+    border: &Border<T>,
+    linear: bool,
+    values: &mut [[f32; 4]],
+) {
+    let contiguous = image
+        .contiguous()
+        .filter(|_| matches!(filter, Filter::Nearest | Filter::Bilinear));
+    let Some((samples, channels)) = contiguous else {
+        for ((value, &x), &y) in values.iter_mut().zip(xs).zip(ys) {
+            *value = sample(image, x, y, filter, border, linear);
+        }
+        return;
+    };
+
+    let (width, height) = image.dimensions();
+    let pixel = |x: usize, y: usize| {
+        let start = (y * width + x) * channels;
+        let pixel = &samples[start..start + channels];
+        blendable(images::to_rgba(channels, |channel| pixel[channel]), linear)
+    };
+
+    // The last first tap along each axis that keeps all taps inside the image
+    let taps = if filter == Filter::Nearest { 1.0 } else { 2.0 };
+    let (last_x, last_y) = (width as f32 - taps, height as f32 - taps);
+
+    let chunks = xs.chunks(LANES).zip(ys.chunks(LANES));
+    for ((xs, ys), values) in chunks.zip(values.chunks_mut(LANES)) {
+        // The first taps along both axes and their weights, computed like in `sample` and `taps`
+        let mut first_x = [0.0f32; LANES];
+        let mut first_y = [0.0f32; LANES];
+        let mut weights = [[0.0f32; 4]; LANES];
+        let mut inside = [false; LANES];
+
+        if filter == Filter::Nearest {
+            for (first_x, &x) in first_x.iter_mut().zip(xs) {
+                *first_x = floor(x + 0.5);
+            }
+            for (first_y, &y) in first_y.iter_mut().zip(ys) {
+                *first_y = floor(y + 0.5);
+            }
+        } else {
+            for (first_x, &x) in first_x.iter_mut().zip(xs) {
+                *first_x = floor(x - 1.0) + 1.0;
+            }
+            for (first_y, &y) in first_y.iter_mut().zip(ys) {
+                *first_y = floor(y - 1.0) + 1.0;
+            }
+
+            let weight = |distance: f32| (1.0 - distance.abs()).max(0.0);
+            let positions = xs.iter().zip(ys).zip(first_x.iter().zip(&first_y));
+            for (weights, ((&x, &y), (&first_x, &first_y))) in weights.iter_mut().zip(positions) {
+                *weights = [
+                    weight(x - first_x),
+                    weight(x - (first_x + 1.0)),
+                    weight(y - first_y),
+                    weight(y - (first_y + 1.0)),
+                ];
+            }
+        }
+
+        let positions = xs.iter().zip(ys).zip(first_x.iter().zip(&first_y));
+        for (inside, ((&x, &y), (&first_x, &first_y))) in inside.iter_mut().zip(positions) {
+            *inside = samplable(x, y)
+                && first_x >= 0.0
+                && first_y >= 0.0
+                && first_x <= last_x
+                && first_y <= last_y;
+        }
+
+        for (i, value) in values.iter_mut().enumerate() {
+            if !inside[i] {
+                *value = sample(image, xs[i], ys[i], filter, border, linear);
+                continue;
+            }
+
+            let (x, y) = (first_x[i] as usize, first_y[i] as usize);
+            if filter == Filter::Nearest {
+                *value = pixel(x, y);
+                continue;
+            }
+
+            // Summed in the same order as in `sample`, so that the values are exactly the same
+            let [x_weight, next_x_weight, y_weight, next_y_weight] = weights[i];
+            let mut sum = [0.0f32; 4];
+            let mut total_weight = 0.0;
+
+            for (y, y_weight) in [(y, y_weight), (y + 1, next_y_weight)] {
+                for (x, x_weight) in [(x, x_weight), (x + 1, next_x_weight)] {
+                    let weight = x_weight * y_weight;
+                    for (value, channel) in sum.iter_mut().zip(pixel(x, y)) {
+                        *value += channel * weight;
+                    }
+                    total_weight += weight;
+                }
+            }
+
+            *value = sum.map(|value| value / total_weight);
+        }
+    }
+}
+
+/// The ellipse covered by a single output pixel in the input image.
+///
+/// The ellipse is never smaller than one input pixel, so that magnifying transformations
+/// behave like regular sampling.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Footprint {
+    /// The quadratic form mapping an offset from the center to its distance in kernel units
+    form: [[f32; 2]; 2],
+
+    /// Half of the width and height of the ellipse's bounding box (with a kernel radius of 1)
+    extent: (f32, f32),
+}
+
+impl Footprint {
+    /// Creates the footprint from the `jacobian` of the output-to-input mapping, in array
+    /// coordinates (Y pointing down): its columns are the steps in the input image when moving by
+    /// one pixel along the X and Y axes of the output image.
+    pub(crate) fn new(jacobian: [[f32; 2]; 2]) -> Self {
+        let [[a, b], [c, d]] = jacobian;
+
+        // The covariance of the ellipse is J * J^T
+        let xx = a * a + b * b;
+        let xy = a * c + b * d;
+        let yy = c * c + d * d;
+
+        // Diagonalize it to clamp the axes of the ellipse to at least one pixel
+        let angle = 0.5 * (2.0 * xy).atan2(xx - yy);
+        let (sin, cos) = angle.sin_cos();
+        let major = (xx * cos * cos + 2.0 * xy * sin * cos + yy * sin * sin).max(1.0);
+        let minor = (xx * sin * sin - 2.0 * xy * sin * cos + yy * cos * cos).max(1.0);
+
+        let form = [
+            [
+                cos * cos / major + sin * sin / minor,
+                sin * cos * (1.0 / major - 1.0 / minor),
+            ],
+            [
+                sin * cos * (1.0 / major - 1.0 / minor),
+                sin * sin / major + cos * cos / minor,
+            ],
+        ];
+
+        let extent = (
+            (cos * cos * major + sin * sin * minor).sqrt(),
+            (sin * sin * major + cos * cos * minor).sqrt(),
+        );
+
+        Footprint { form, extent }
+    }
+
+    /// How many input pixels are read for a single sample with `filter`
+    pub(crate) fn taps(&self, filter: Filter) -> f32 {
+        let radius = filter.radius();
+        (2.0 * radius * self.extent.0).ceil() * (2.0 * radius * self.extent.1).ceil()
+    }
+
+    /// How far from the sampled position (in pixels) a sample with `filter` reads along the X
+    /// and Y axes
+    pub(crate) fn reach(&self, filter: Filter) -> (f32, f32) {
+        let radius = filter.radius();
+        (radius * self.extent.0, radius * self.extent.1)
+    }
+}
+
+/// Samples `image` at the position (`x`, `y`) like [`sample`], averaging all input pixels
+/// inside the `footprint` of the output pixel, weighted by `filter`.
+pub(crate) fn sample_footprint<T: Sample, I: Image<T> + ?Sized>(
+    image: &I,
+    x: f32,
+    y: f32,
+    filter: Filter,
+    border: &Border<T>,
+    footprint: &Footprint,
+    linear: bool,
+) -> [f32; 4] {
+    let (width, height) = image.dimensions();
+
+    if !samplable(x, y) || !border.covers(x, y, (width, height)) {
+        return blendable(border.color, linear);
+    }
+
+    let radius = filter.radius();
+    let [[xx, xy], [_, yy]] = footprint.form;
+
+    // Footprints can get huge close to the horizon of a perspective transformation, so only the
+    // image and the border within one image size around it are visited
+    let (width, height) = (width as isize, height as isize);
+    let first_x = ((x - radius * footprint.extent.0).floor() as isize + 1).max(-width);
+    let last_x = ((x + radius * footprint.extent.0).floor() as isize).min(2 * width - 1);
+    let first_y = ((y - radius * footprint.extent.1).floor() as isize + 1).max(-height);
+    let last_y = ((y + radius * footprint.extent.1).floor() as isize).min(2 * height - 1);
+
+    let mut sum = [0.0f32; 4];
+    let mut total_weight = 0.0;
+
+    for tap_y in first_y..=last_y {
+        let dy = tap_y as f32 - y;
+
+        for tap_x in first_x..=last_x {
+            let dx = tap_x as f32 - x;
+            let distance = (xx * dx * dx + 2.0 * xy * dx * dy + yy * dy * dy).sqrt();
+
+            let weight = filter.weight(distance);
+            if weight == 0.0 {
+                continue;
+            }
+
+            let pixel = blendable(border.pixel(image, tap_x, tap_y), linear);
+            for (value, channel) in sum.iter_mut().zip(pixel) {
+                *value += channel * weight;
+            }
+            total_weight += weight;
+        }
+    }
+
+    if total_weight == 0.0 {
+        return sample(image, x, y, filter, border, linear);
+    }
+
+    sum.map(|value| value / total_weight)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::images;
+
+    #[test]
+    fn taps_cover_the_kernel_width() {
+        // Rounding `pos - 3` and `pos + 3` separately spans 7 pixels here
+        let pos = f32::from_bits(2.0f32.to_bits() - 1);
+
+        let (indices, _, count) = taps(Filter::Lanczos3, pos);
+        assert_eq!(count, 6);
+        assert_eq!(indices, [-1, 0, 1, 2, 3, 4]);
+
+        for &filter in Filter::value_variants() {
+            for pos in [0.0, 0.5, pos, 1e6 + 0.25, -3.75] {
+                let (_, _, count) = taps(filter, pos);
+                assert_eq!(count, (2.0 * filter.radius()) as usize);
+            }
+        }
+    }
+
+    #[test]
+    fn samples_unreachable_positions_as_the_border() {
+        let image = images::filled::<u8>((4, 4), 4, [10, 20, 30, 255]);
+
+        for &edge in Edge::value_variants() {
+            let border = Border {
+                edge,
+                color: [1, 2, 3, 255],
+            };
+
+            for (x, y) in [
+                (f32::INFINITY, 1.0),
+                (1.0, f32::NEG_INFINITY),
+                (f32::NAN, f32::NAN),
+                (1e30, -1e30),
+            ] {
+                for &filter in Filter::value_variants() {
+                    let values = sample(&image, x, y, filter, &border, false);
+                    assert_eq!(values, [1.0, 2.0, 3.0, 255.0], "{edge:?} {filter:?}");
+                }
+
+                let footprint = Footprint::new([[1e20, 0.0], [0.0, 1e20]]);
+                let values =
+                    sample_footprint(&image, x, y, Filter::Lanczos3, &border, &footprint, false);
+                assert_eq!(values, [1.0, 2.0, 3.0, 255.0], "{edge:?}");
+            }
+        }
+    }
+}
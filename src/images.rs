@@ -1,34 +1,680 @@
-use std::path::PathBuf;
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
 
-use color_eyre::{eyre::ContextCompat, Result};
-use image::{ImageReader, RgbaImage};
-use ndarray::Array3;
+use clap::ValueEnum;
+use color_eyre::{
+    eyre::{bail, ContextCompat},
+    Result,
+};
+use image::{ColorType, DynamicImage, ImageBuffer, ImageDecoder, ImageError, ImageReader};
+use ndarray::{Array3, Axis, Zip};
 
-pub(crate) type ImageArray = ndarray::ArrayBase<ndarray::OwnedRepr<u8>, ndarray::Dim<[usize; 3]>>;
+use crate::{srgb, stream};
 
-pub(crate) fn load_image(path: &PathBuf) -> Result<(ImageArray, (usize, usize))> {
-    let img = ImageReader::open(path)?.decode()?.into_rgba8();
+pub(crate) type ImageArray<T> = Array3<T>;
 
-    let (width, height) = (img.width() as usize, img.height() as usize);
+/// The type of the samples of an image
+pub(crate) trait Sample:
+    Copy + Default + PartialEq + Send + Sync + fmt::Debug + 'static
+{
+    /// The value of a fully saturated channel, which is 1 for floating point samples
+    const MAX: f32;
+
+    fn to_f32(self) -> f32;
+
+    /// Rounds the value and clamps it to the range of the type, floating point samples are kept
+    /// as they are
+    fn from_f32(value: f32) -> Self;
+
+    fn wrap(array: ImageArray<Self>) -> AnyImage;
+
+    /// Converts the sRGB encoded sample into linear light in the range of the type, floating
+    /// point samples are already in linear light
+    fn to_linear(self) -> f32;
+
+    /// Converts linear light in the range of the type into an sRGB encoded sample
+    fn from_linear(value: f32) -> Self {
+        Self::from_f32(srgb::to_srgb(value / Self::MAX) * Self::MAX)
+    }
+
+    /// Converts the sample to another type, scaling it to its range
+    fn convert<T: Sample>(self) -> T {
+        T::from_f32(self.to_f32() * (T::MAX / Self::MAX))
+    }
+
+    /// A fully opaque alpha channel
+    fn opaque() -> Self {
+        Self::from_f32(Self::MAX)
+    }
+}
+
+impl Sample for u8 {
+    const MAX: f32 = u8::MAX as f32;
+
+    fn to_f32(self) -> f32 {
+        self as f32
+    }
+
+    fn from_f32(value: f32) -> Self {
+        value.round().clamp(0.0, u8::MAX as f32) as u8
+    }
+
+    fn wrap(array: ImageArray<Self>) -> AnyImage {
+        AnyImage::U8(array)
+    }
+
+    fn to_linear(self) -> f32 {
+        srgb::TO_LINEAR_8[self as usize]
+    }
+}
+
+impl Sample for u16 {
+    const MAX: f32 = u16::MAX as f32;
+
+    fn to_f32(self) -> f32 {
+        self as f32
+    }
+
+    fn from_f32(value: f32) -> Self {
+        value.round().clamp(0.0, u16::MAX as f32) as u16
+    }
+
+    fn wrap(array: ImageArray<Self>) -> AnyImage {
+        AnyImage::U16(array)
+    }
+
+    fn to_linear(self) -> f32 {
+        srgb::TO_LINEAR_16[self as usize]
+    }
+}
+
+impl Sample for f32 {
+    const MAX: f32 = 1.0;
+
+    fn to_f32(self) -> f32 {
+        self
+    }
+
+    fn from_f32(value: f32) -> Self {
+        value
+    }
+
+    fn wrap(array: ImageArray<Self>) -> AnyImage {
+        AnyImage::F32(array)
+    }
+
+    fn to_linear(self) -> f32 {
+        self
+    }
+
+    fn from_linear(value: f32) -> Self {
+        value
+    }
+}
+
+/// How many bits the samples of an image have
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Depth {
+    /// 8 bits per sample
+    #[value(name = "8")]
+    Eight,
 
-    let array = Array3::<u8>::from_shape_vec((height, width, 4), img.as_raw().to_vec())?;
+    /// 16 bits per sample (PNG, TIFF and PNM)
+    #[value(name = "16")]
+    Sixteen,
 
-    Ok((array, (width, height)))
+    /// 32-bit floating point samples, which aren't clamped (EXR, HDR and TIFF)
+    #[value(name = "32f")]
+    Float,
 }
 
-pub(crate) fn save_image(array: ImageArray, path: &PathBuf) -> Result<()> {
-    let array = array.as_standard_layout().into_owned();
+impl Depth {
+    /// The depths to try in order when an image of this depth is saved in a format that can't
+    /// store it, starting with the ones that don't lose precision
+    fn fallbacks(self) -> [Depth; 3] {
+        match self {
+            Depth::Eight => [Depth::Eight, Depth::Sixteen, Depth::Float],
+            Depth::Sixteen => [Depth::Sixteen, Depth::Float, Depth::Eight],
+            Depth::Float => [Depth::Float, Depth::Sixteen, Depth::Eight],
+        }
+    }
+
+    pub(crate) fn bytes(self) -> usize {
+        match self {
+            Depth::Eight => 1,
+            Depth::Sixteen => 2,
+            Depth::Float => 4,
+        }
+    }
+}
+
+/// How the pixels of an image are stored: the depth of their samples and how many channels they
+/// have (1 for gray, 2 for gray and alpha, 3 for RGB and 4 for RGBA)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Layout {
+    pub(crate) depth: Depth,
+    pub(crate) channels: usize,
+}
+
+impl Layout {
+    pub(crate) fn of(color: ColorType) -> Self {
+        let channels = color.channel_count() as usize;
+        let depth = match color.bytes_per_pixel() as usize / channels {
+            1 => Depth::Eight,
+            2 => Depth::Sixteen,
+            _ => Depth::Float,
+        };
+
+        Layout { depth, channels }
+    }
+
+    pub(crate) fn has_color(&self) -> bool {
+        self.channels >= 3
+    }
+
+    pub(crate) fn has_alpha(&self) -> bool {
+        matches!(self.channels, 2 | 4)
+    }
+
+    /// The amount of channels with color and alpha added as requested
+    pub(crate) fn widened(&self, color: bool, alpha: bool) -> usize {
+        channels(self.has_color() || color, self.has_alpha() || alpha)
+    }
+
+    pub(crate) fn bytes_per_pixel(&self) -> usize {
+        self.depth.bytes() * self.channels
+    }
+}
+
+impl fmt::Display for Depth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Depth::Eight => "8-bit",
+            Depth::Sixteen => "16-bit",
+            Depth::Float => "32-bit float",
+        })
+    }
+}
+
+impl fmt::Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let depth = self.depth;
+        let channels = match self.channels {
+            1 => "gray",
+            2 => "gray and alpha",
+            3 => "RGB",
+            _ => "RGBA",
+        };
+
+        write!(f, "{depth} {channels}")
+    }
+}
+
+/// Expands a pixel with `channels` channels (gray, gray and alpha, RGB or RGBA) to RGBA, reading
+/// its samples by their index
+pub(crate) fn to_rgba<T: Sample>(channels: usize, sample: impl Fn(usize) -> T) -> [T; 4] {
+    match channels {
+        1 => {
+            let l = sample(0);
+            [l, l, l, T::opaque()]
+        }
+        2 => {
+            let l = sample(0);
+            [l, l, l, sample(1)]
+        }
+        3 => [sample(0), sample(1), sample(2), T::opaque()],
+        _ => [sample(0), sample(1), sample(2), sample(3)],
+    }
+}
+
+/// Converts an RGBA pixel into the first `channels` samples of a pixel, which is the opposite of
+/// [`to_rgba`]. Gray pixels are only made of gray colors, so red stands for all of them.
+pub(crate) fn from_rgba<T: Sample>(rgba: [T; 4], channels: usize) -> [T; 4] {
+    let [r, _, _, a] = rgba;
+
+    match channels {
+        1 => [r; 4],
+        2 => [r, a, a, a],
+        _ => rgba,
+    }
+}
+
+/// An RGBA image that pixels can be read from, whether it's decoded completely or only partially
+pub(crate) trait Image<T: Sample>: Sync {
+    /// The width and height of the image
+    fn dimensions(&self) -> (usize, usize);
+
+    /// Reads the pixel at (`x`, `y`) as RGBA, with Y pointing down like the rows of the array
+    fn pixel(&self, x: usize, y: usize) -> [T; 4];
+
+    /// The samples of the whole image row after row along with the amount of channels, if
+    /// they're stored that way
+    fn contiguous(&self) -> Option<(&[T], usize)> {
+        None
+    }
+}
+
+impl<T: Sample> Image<T> for ImageArray<T> {
+    fn dimensions(&self) -> (usize, usize) {
+        let (height, width, _) = self.dim();
+        (width, height)
+    }
+
+    fn pixel(&self, x: usize, y: usize) -> [T; 4] {
+        let channels = self.dim().2;
+
+        // Sampling reads many pixels, so the most common layout is read without converting it
+        if channels == 4 {
+            return std::array::from_fn(|channel| self[[y, x, channel]]);
+        }
+
+        to_rgba(channels, |channel| self[[y, x, channel]])
+    }
 
+    fn contiguous(&self) -> Option<(&[T], usize)> {
+        self.as_slice().map(|samples| (samples, self.dim().2))
+    }
+}
+
+/// The amount of channels of pixels with or without color and alpha
+pub(crate) fn channels(color: bool, alpha: bool) -> usize {
+    (if color { 3 } else { 1 }) + alpha as usize
+}
+
+/// The amount of channels needed to store the RGBA `color`
+pub(crate) fn color_channels([r, g, b, a]: [u8; 4]) -> usize {
+    channels(r != g || g != b, a < u8::MAX)
+}
+
+/// Creates a `width`x`height` image with `channels` channels filled with the RGBA `color`
+pub(crate) fn filled<T: Sample>(
+    (width, height): (usize, usize),
+    channels: usize,
+    color: [u8; 4],
+) -> ImageArray<T> {
+    let pixel = from_rgba(color.map(u8::convert), channels);
+
+    ImageArray::from_shape_fn((height, width, channels), |(_, _, channel)| pixel[channel])
+}
+
+/// Converts the samples of `array` to another type and its pixels to `channels` channels
+pub(crate) fn convert<S: Sample, T: Sample>(
+    array: &ImageArray<S>,
+    channels: usize,
+) -> ImageArray<T> {
+    let (height, width, from) = array.dim();
+    let mut converted = ImageArray::default((height, width, channels));
+
+    Zip::from(converted.lanes_mut(Axis(2)))
+        .and(array.lanes(Axis(2)))
+        .par_for_each(|mut pixel, samples| {
+            let rgba = to_rgba(from, |channel| samples[channel]);
+
+            for (value, sample) in pixel.iter_mut().zip(from_rgba(rgba, channels)) {
+                *value = sample.convert();
+            }
+        });
+
+    converted
+}
+
+/// A decoded image with the type of its samples
+pub(crate) enum AnyImage {
+    U8(ImageArray<u8>),
+    U16(ImageArray<u16>),
+    F32(ImageArray<f32>),
+}
+
+impl AnyImage {
+    pub(crate) fn layout(&self) -> Layout {
+        let (depth, (_, _, channels)) = match self {
+            AnyImage::U8(array) => (Depth::Eight, array.dim()),
+            AnyImage::U16(array) => (Depth::Sixteen, array.dim()),
+            AnyImage::F32(array) => (Depth::Float, array.dim()),
+        };
+
+        Layout { depth, channels }
+    }
+
+    /// The samples of the image converted to another type and `channels` channels, row by row
+    fn samples<T: Sample>(&self, channels: usize) -> Vec<T> {
+        self.converted(channels).into_raw_vec_and_offset().0
+    }
+
+    /// Converts the samples of the image to another type and its pixels to `channels` channels
+    pub(crate) fn converted<T: Sample>(&self, channels: usize) -> ImageArray<T> {
+        match self {
+            AnyImage::U8(array) => convert(array, channels),
+            AnyImage::U16(array) => convert(array, channels),
+            AnyImage::F32(array) => convert(array, channels),
+        }
+    }
+
+    /// Converts the image to the `layout`, or [`None`] if the image crate can't hold it
+    fn to_dynamic(&self, layout: Layout) -> Option<DynamicImage> {
+        let (height, width, _) = match self {
+            AnyImage::U8(array) => array.dim(),
+            AnyImage::U16(array) => array.dim(),
+            AnyImage::F32(array) => array.dim(),
+        };
+        let (width, height) = (width as u32, height as u32);
+        let channels = layout.channels;
+
+        Some(match (layout.depth, channels) {
+            (Depth::Eight, 1) => DynamicImage::ImageLuma8(ImageBuffer::from_raw(
+                width,
+                height,
+                self.samples(channels),
+            )?),
+            (Depth::Eight, 2) => DynamicImage::ImageLumaA8(ImageBuffer::from_raw(
+                width,
+                height,
+                self.samples(channels),
+            )?),
+            (Depth::Eight, 3) => DynamicImage::ImageRgb8(ImageBuffer::from_raw(
+                width,
+                height,
+                self.samples(channels),
+            )?),
+            (Depth::Eight, _) => DynamicImage::ImageRgba8(ImageBuffer::from_raw(
+                width,
+                height,
+                self.samples(channels),
+            )?),
+            (Depth::Sixteen, 1) => DynamicImage::ImageLuma16(ImageBuffer::from_raw(
+                width,
+                height,
+                self.samples(channels),
+            )?),
+            (Depth::Sixteen, 2) => DynamicImage::ImageLumaA16(ImageBuffer::from_raw(
+                width,
+                height,
+                self.samples(channels),
+            )?),
+            (Depth::Sixteen, 3) => DynamicImage::ImageRgb16(ImageBuffer::from_raw(
+                width,
+                height,
+                self.samples(channels),
+            )?),
+            (Depth::Sixteen, _) => DynamicImage::ImageRgba16(ImageBuffer::from_raw(
+                width,
+                height,
+                self.samples(channels),
+            )?),
+            (Depth::Float, 3) => DynamicImage::ImageRgb32F(ImageBuffer::from_raw(
+                width,
+                height,
+                self.samples(channels),
+            )?),
+            (Depth::Float, 4) => DynamicImage::ImageRgba32F(ImageBuffer::from_raw(
+                width,
+                height,
+                self.samples(channels),
+            )?),
+            // There are no gray floating point images
+            (Depth::Float, _) => return None,
+        })
+    }
+}
+
+/// Decodes the image in its own depth and channels
+pub(crate) fn load_image(path: &PathBuf) -> Result<(AnyImage, (usize, usize))> {
+    let img = match ImageReader::open(path)?.decode() {
+        Ok(img) => img,
+        // The image crate can't decode TIFF images with floating point samples, but they can be
+        // read row by row
+        Err(e @ ImageError::Unsupported(_)) => {
+            let Ok(mut reader) = stream::open(path) else {
+                return Err(e.into());
+            };
+            let image = stream::read_image(reader.as_mut())?;
+
+            return Ok((image, reader.dimensions()));
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    let (width, height) = (img.width() as usize, img.height() as usize);
+    let shape = (height, width, img.color().channel_count() as usize);
+
+    let image = match img {
+        DynamicImage::ImageLuma8(img) => {
+            AnyImage::U8(Array3::from_shape_vec(shape, img.into_raw())?)
+        }
+        DynamicImage::ImageLumaA8(img) => {
+            AnyImage::U8(Array3::from_shape_vec(shape, img.into_raw())?)
+        }
+        DynamicImage::ImageRgb8(img) => {
+            AnyImage::U8(Array3::from_shape_vec(shape, img.into_raw())?)
+        }
+        DynamicImage::ImageRgba8(img) => {
+            AnyImage::U8(Array3::from_shape_vec(shape, img.into_raw())?)
+        }
+        DynamicImage::ImageLuma16(img) => {
+            AnyImage::U16(Array3::from_shape_vec(shape, img.into_raw())?)
+        }
+        DynamicImage::ImageLumaA16(img) => {
+            AnyImage::U16(Array3::from_shape_vec(shape, img.into_raw())?)
+        }
+        DynamicImage::ImageRgb16(img) => {
+            AnyImage::U16(Array3::from_shape_vec(shape, img.into_raw())?)
+        }
+        DynamicImage::ImageRgba16(img) => {
+            AnyImage::U16(Array3::from_shape_vec(shape, img.into_raw())?)
+        }
+        DynamicImage::ImageRgb32F(img) => {
+            AnyImage::F32(Array3::from_shape_vec(shape, img.into_raw())?)
+        }
+        DynamicImage::ImageRgba32F(img) => {
+            AnyImage::F32(Array3::from_shape_vec(shape, img.into_raw())?)
+        }
+        img => AnyImage::U8(Array3::from_shape_vec(
+            (height, width, 4),
+            img.into_rgba8().into_raw(),
+        )?),
+    };
+
+    Ok((image, (width, height)))
+}
+
+/// Writes the whole `array` at once with [`stream::write_image`]
+fn write_array<T: Sample>(
+    array: &ImageArray<T>,
+    path: &Path,
+    layout: Layout,
+) -> Result<((), Layout)> {
     let (height, width, _) = array.dim();
 
-    let (flattened, _) = array.into_raw_vec_and_offset();
+    stream::write_image(path, (width, height), layout, |write| {
+        write(
+            array
+                .as_slice()
+                .wrap_err("Failed to create image from array")?,
+        )
+    })
+}
+
+/// Reads only the dimensions and the layout of the image from its header, without decoding it
+pub(crate) fn read_header(path: &PathBuf) -> Result<((usize, usize), Layout)> {
+    let decoder = match ImageReader::open(path)?
+        .with_guessed_format()?
+        .into_decoder()
+    {
+        Ok(decoder) => decoder,
+        Err(e @ ImageError::Unsupported(_)) => {
+            let Ok(reader) = stream::open(path) else {
+                return Err(e.into());
+            };
+
+            return Ok((reader.dimensions(), reader.layout()));
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    let (width, height) = decoder.dimensions();
+
+    Ok((
+        (width as usize, height as usize),
+        Layout::of(decoder.color_type()),
+    ))
+}
+
+/// What the images of a format can store, for the formats that can't store every layout
+struct Limits {
+    name: &'static str,
+    depths: &'static [Depth],
+    alpha: bool,
+}
+
+/// The limits of the format images are saved in at `path`, by its extension
+fn limits(path: &Path) -> Option<Limits> {
+    let extension = path.extension()?.to_str()?.to_lowercase();
 
-    let output_img = RgbaImage::from_raw(width as u32, height as u32, flattened)
-        .wrap_err("Failed to create image from array")?;
+    let (name, depths, alpha): (_, &[Depth], _) = match extension.as_str() {
+        "png" => ("PNG", &[Depth::Eight, Depth::Sixteen], true),
+        "pam" => ("PAM", &[Depth::Eight, Depth::Sixteen], true),
+        "pgm" | "ppm" | "pnm" => ("PGM and PPM", &[Depth::Eight, Depth::Sixteen], false),
+        "jpg" | "jpeg" => ("JPEG", &[Depth::Eight], false),
+        "hdr" => ("Radiance HDR", &[Depth::Float], false),
+        _ => return None,
+    };
 
-    output_img.save(path)?;
+    Some(Limits {
+        name,
+        depths,
+        alpha,
+    })
+}
 
-    std::thread::sleep(std::time::Duration::from_secs(1));
+/// Checks that an image with the `layout` can be saved at `path`, in the given `depth` if there
+/// is one, and names what the format can't store otherwise
+pub(crate) fn check_format(path: &Path, layout: Layout, depth: Option<Depth>) -> Result<()> {
+    let Some(limits) = limits(path) else {
+        return Ok(());
+    };
+
+    if let Some(depth) = depth.filter(|depth| !limits.depths.contains(depth)) {
+        let depths: Vec<String> = limits.depths.iter().map(Depth::to_string).collect();
+        bail!(
+            "{} images can't have {depth} samples, only {}, so choose another --output-depth",
+            limits.name,
+            depths.join(" or ")
+        );
+    }
+
+    if layout.has_alpha() && !limits.alpha {
+        bail!(
+            "{} images can't be transparent, give the output image an opaque --background",
+            limits.name
+        );
+    }
 
     Ok(())
 }
+
+/// Saves the image in its own layout, or in the given `depth`.
+///
+/// If the format can't store the layout, gray images are saved as RGB, then alpha is added, and
+/// without a `depth` the other depths are tried. Returns the layout the image was saved in.
+pub(crate) fn save_image(image: AnyImage, path: &PathBuf, depth: Option<Depth>) -> Result<Layout> {
+    let layout = image.layout();
+    check_format(path, layout, depth)?;
+
+    // The PNM encoder of the image crate only writes 16-bit samples for gray images, and the TIFF
+    // encoder no floating point samples, so those are written like the bands of images rendered
+    // with a memory budget
+    let pnm = path.extension().is_some_and(|extension| {
+        ["pam", "pgm", "ppm", "pnm"]
+            .iter()
+            .any(|pnm| extension.eq_ignore_ascii_case(pnm))
+    });
+    let float_tiff = depth.unwrap_or(layout.depth) == Depth::Float && stream::writes_float(path);
+    if pnm || float_tiff {
+        let layout = Layout {
+            depth: depth.unwrap_or(match layout.depth {
+                Depth::Float if !float_tiff => Depth::Sixteen,
+                depth => depth,
+            }),
+            ..layout
+        };
+
+        let ((), layout) = match &image {
+            AnyImage::U8(array) => write_array(array, path, layout)?,
+            AnyImage::U16(array) => write_array(array, path, layout)?,
+            AnyImage::F32(array) => write_array(array, path, layout)?,
+        };
+
+        return Ok(layout);
+    }
+
+    let depths = match depth {
+        Some(depth) => vec![depth],
+        None => layout.depth.fallbacks().to_vec(),
+    };
+
+    let mut layouts: Vec<Layout> = Vec::new();
+    for depth in depths {
+        for channels in [
+            layout.channels,
+            layout.widened(true, false),
+            layout.widened(true, true),
+        ] {
+            let layout = Layout { depth, channels };
+            if !layouts.contains(&layout) {
+                layouts.push(layout);
+            }
+        }
+    }
+
+    // Encoders reject layouts they can't store with all kinds of errors, even I/O errors, so
+    // every layout is tried and the first error is reported
+    let mut unsupported = None;
+    for layout in layouts {
+        let Some(output_img) = image.to_dynamic(layout) else {
+            continue;
+        };
+
+        match output_img.save(path) {
+            Ok(()) => {
+                std::thread::sleep(std::time::Duration::from_secs(1));
+
+                return Ok(layout);
+            }
+            Err(e) => {
+                unsupported.get_or_insert(e);
+            }
+        }
+    }
+
+    Err(unsupported
+        .wrap_err("Failed to create image from array")?
+        .into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_what_formats_can_store() {
+        let rgba = Layout {
+            depth: Depth::Eight,
+            channels: 4,
+        };
+        let rgb = Layout {
+            channels: 3,
+            ..rgba
+        };
+
+        assert!(check_format(Path::new("out.png"), rgba, None).is_ok());
+        assert!(check_format(Path::new("out.png"), rgb, Some(Depth::Float)).is_err());
+        assert!(check_format(Path::new("out.HDR"), rgba, None).is_err());
+        assert!(check_format(Path::new("out.hdr"), rgb, None).is_ok());
+        assert!(check_format(Path::new("out.jpg"), rgba, None).is_err());
+        assert!(check_format(Path::new("out.ppm"), rgba, None).is_err());
+        assert!(check_format(Path::new("out.ppm"), rgb, Some(Depth::Sixteen)).is_ok());
+        assert!(check_format(Path::new("out.tif"), rgba, Some(Depth::Float)).is_ok());
+    }
+}
@@ -0,0 +1,413 @@
+use std::path::PathBuf;
+
+use clap::ValueEnum;
+use color_eyre::Result;
+use ndarray::Array2;
+use owo_colors::OwoColorize as _;
+
+use crate::{
+    exact::Exact,
+    filter::{Blend, Border, Compositing, Edge},
+    images::{self, AnyImage, Depth, ImageArray, Layout, Sample},
+    matrix_ext::{MatrixError, MatrixExt},
+    pipeline::{self, Pipeline},
+    render::{self, Coverage, Sampling},
+    transform, CHECKMARK,
+};
+
+/// An image placed onto the output image besides the input image, given as its path followed by
+/// its settings and transformations like `sticker.png; rotate 30deg; offset 10,20; opacity 0.5`
+#[derive(Clone, Debug)]
+pub(crate) struct Layer {
+    pub(crate) path: PathBuf,
+
+    /// The transformations of the layer, like --transform
+    pub(crate) pipeline: Pipeline,
+
+    /// The amount to offset the layer by after transforming it, like --offset
+    pub(crate) offset: [f32; 2],
+
+    /// Layers with a lower z-order are drawn below the ones with a higher z-order, and the ones
+    /// with a negative z-order below the input image
+    pub(crate) z: isize,
+
+    pub(crate) compositing: Compositing,
+}
+
+impl Layer {
+    /// The matrix the layer is transformed with, for an image of the given size
+    pub(crate) fn matrix(&self, dims: (usize, usize)) -> Array2<f32> {
+        let [x, y] = self.offset;
+
+        transform::translation(x, y).dot(&self.pipeline.matrix(dims))
+    }
+}
+
+/// Parses a layer: the path of its image, optionally followed by a semicolon and the steps of a
+/// pipeline mixed with `offset X Y`, `z N`, `opacity O` and `blend MODE`
+pub(crate) fn parse(s: &str) -> Result<Layer, String> {
+    // The steps are parsed within the whole layer, so that errors point into it
+    let (path, steps) = match s.find(';') {
+        Some(end) => (&s[..end], end + 1),
+        None => (s, s.len()),
+    };
+    let path = path.trim();
+    if path.is_empty() {
+        return Err("Expected the path of an image".to_string());
+    }
+
+    let mut offset = [0.0; 2];
+    let mut z = 0;
+    let mut compositing = Compositing::default();
+
+    let pipeline = pipeline::parse_with(s, steps, |name, args| {
+        let setting = match (name, args) {
+            ("offset", [x, y]) => crate::parse_num(x)
+                .and_then(|x| Ok([x, crate::parse_num(y)?]))
+                .map(|value| offset = value),
+            ("z", [value]) => crate::parse_num(value).map(|value| z = value),
+            ("opacity", [value]) => {
+                crate::parse_opacity(value).map(|value| compositing.opacity = value)
+            }
+            ("blend", [mode]) => Blend::from_str(mode, true)
+                .map(|mode| compositing.blend = mode)
+                .map_err(|_| {
+                    format!(
+                        "Unknown blend mode `{mode}` (expected normal, multiply, screen or add)"
+                    )
+                }),
+            ("offset", _) => Err(format!("`offset` expects 2 values, got {}", args.len())),
+            ("z" | "opacity" | "blend", _) => {
+                Err(format!("`{name}` expects 1 value, got {}", args.len()))
+            }
+            _ => return None,
+        };
+
+        Some(setting)
+    })
+    .map_err(|e| e.to_string())?;
+
+    Ok(Layer {
+        path: path.into(),
+        pipeline,
+        offset,
+        z,
+        compositing,
+    })
+}
+
+/// The layers in the order they're drawn in, from the bottom up. Layers with the same z-order are
+/// drawn in the order they're given.
+pub(crate) fn in_z_order(layers: &[Layer]) -> Vec<&Layer> {
+    let mut layers: Vec<&Layer> = layers.iter().collect();
+    layers.sort_by_key(|layer| layer.z);
+
+    layers
+}
+
+/// An image drawn onto the output image besides the input image, through its own matrix
+pub(crate) struct Placed {
+    path: PathBuf,
+
+    /// The image, which is only left unloaded in a dry run
+    image: Option<AnyImage>,
+    pub(crate) dims: (usize, usize),
+    layout: Layout,
+
+    matrix: Array2<f32>,
+    inverse: Array2<f32>,
+    compositing: Compositing,
+}
+
+impl Placed {
+    /// Loads the image at `path`, or only reads its header in a dry run. It's placed as it is
+    /// until it's [`Placed::transformed`].
+    pub(crate) fn load(path: &PathBuf, dry_run: bool) -> Result<Self> {
+        println!(
+            "{}",
+            format!("Loading image: {}...", path.display().yellow()).blue()
+        );
+
+        let (image, dims, layout) = if dry_run {
+            let (dims, layout) = images::read_header(path)?;
+            (None, dims, layout)
+        } else {
+            let (image, dims) = images::load_image(path)?;
+            let layout = image.layout();
+            (Some(image), dims, layout)
+        };
+
+        println!(
+            "{} {} {}",
+            CHECKMARK.green(),
+            if image.is_none() {
+                "Read image header with dimensions:"
+            } else {
+                "Loaded image with dimensions:"
+            }
+            .green(),
+            format!("({}, {}), {}", dims.0, dims.1, layout).yellow()
+        );
+
+        Ok(Placed {
+            path: path.clone(),
+            image,
+            dims,
+            layout,
+            matrix: transform::identity(),
+            inverse: transform::identity(),
+            compositing: Compositing::default(),
+        })
+    }
+
+    /// Places the image through `matrix` instead, composited according to `compositing`.
+    ///
+    /// Fails if the matrix can't be inverted.
+    pub(crate) fn transformed(
+        self,
+        matrix: Array2<f32>,
+        compositing: Compositing,
+    ) -> Result<Self, MatrixError> {
+        let mut inverse = matrix.clone();
+        inverse.invert()?;
+
+        Ok(Placed {
+            matrix,
+            inverse,
+            compositing,
+            ..self
+        })
+    }
+
+    /// How the image is sampled and composited: like the input image, but without reading
+    /// anything beyond its edges
+    fn sampling<T: Sample>(&self, sampling: &Sampling<T>) -> Sampling<T> {
+        Sampling {
+            border: Border {
+                edge: Edge::Transparent,
+                color: [T::default(); 4],
+            },
+            compositing: self.compositing,
+            ..*sampling
+        }
+    }
+
+    /// The amount of channels an `out_width`x`out_height` output image needs after drawing the
+    /// image over one with `canvas` channels
+    pub(crate) fn channels(
+        &self,
+        canvas: usize,
+        out_dims: (usize, usize),
+        sampling: &Sampling,
+    ) -> usize {
+        let sampling = self.sampling(sampling);
+        let coverage = match Exact::new(&self.matrix).filter(|exact| exact.preserves(&sampling)) {
+            Some(exact) => exact.coverage(self.dims, out_dims),
+            None => Coverage::find(&self.inverse, self.dims, out_dims, &sampling),
+        };
+
+        Coverage::layout(Some(coverage), self.layout, &sampling, canvas).channels
+    }
+
+    /// The memory the image takes once loaded, and the memory of the copy in `depth` it's drawn
+    /// from
+    pub(crate) fn memory(&self, depth: Depth) -> (u128, u128) {
+        let pixels = self.dims.0 as u128 * self.dims.1 as u128;
+
+        (
+            pixels * self.layout.bytes_per_pixel() as u128,
+            pixels * self.layout.channels as u128 * depth.bytes() as u128,
+        )
+    }
+
+    /// Draws the image onto the `output` image
+    pub(crate) fn draw<T: Sample>(&self, output: &mut ImageArray<T>, sampling: &Sampling<T>) {
+        println!(
+            "{}",
+            format!("Rendering image: {}...", self.path.display().yellow()).blue()
+        );
+
+        let image = self
+            .image
+            .as_ref()
+            .expect("images are only left unloaded in a dry run");
+        let image = image.converted::<T>(self.layout.channels);
+        let sampling = self.sampling(sampling);
+
+        match Exact::new(&self.matrix).filter(|exact| exact.preserves(&sampling)) {
+            Some(exact) => exact.render(&image, output, &sampling),
+            None => {
+                let (out_height, out_width, _) = output.dim();
+                let pb = render::progress_bar(out_width * out_height);
+                render::gather(&image, output, 0, out_height, &self.inverse, &sampling, &pb);
+                pb.finish();
+            }
+        }
+    }
+}
+
+/// Everything drawn onto the output image besides the input image
+pub(crate) struct Scene {
+    /// The background color
+    pub(crate) color: [u8; 4],
+
+    /// The images drawn below the input image, from the bottom up
+    pub(crate) below: Vec<Placed>,
+
+    /// The images drawn above the input image, from the bottom up
+    pub(crate) above: Vec<Placed>,
+}
+
+impl Scene {
+    /// Adds an image over the ones added before it, below the input image if its z-order `z` is
+    /// negative
+    pub(crate) fn add(&mut self, placed: Placed, z: isize) {
+        if z < 0 {
+            self.below.push(placed);
+        } else {
+            self.above.push(placed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+
+    use super::*;
+    use crate::{filter::Filter, render::tests::sampling};
+
+    #[test]
+    fn parses_the_settings_between_the_steps() {
+        let layer =
+            parse("sticker.png; rotate 90deg; offset 10, 2*10; z -2; opacity 0.25; blend screen")
+                .unwrap();
+
+        assert_eq!(layer.path, PathBuf::from("sticker.png"));
+        assert_eq!(layer.offset, [10.0, 20.0]);
+        assert_eq!(layer.z, -2);
+        assert_eq!(layer.compositing.opacity, 0.25);
+        assert_eq!(layer.compositing.blend, Blend::Screen);
+
+        // The offset is applied after the steps
+        let matrix = transform::translation(10.0, 20.0).dot(&transform::rotation(PI / 2.0));
+        assert_eq!(layer.matrix((4, 3)), matrix);
+
+        let layer = parse(" sticker.png ").unwrap();
+        assert_eq!(layer.path, PathBuf::from("sticker.png"));
+        assert_eq!((layer.offset, layer.z), ([0.0, 0.0], 0));
+        assert_eq!(layer.matrix((4, 3)), transform::identity());
+    }
+
+    #[test]
+    fn points_errors_into_the_whole_layer() {
+        for (source, message, position) in [
+            (
+                "sticker.png; rotat 30",
+                "Unknown operation `rotat`",
+                "column 14",
+            ),
+            (
+                "sticker.png; z 1; opacity 2",
+                "Expected an opacity between 0 and 1, got 2",
+                "column 27",
+            ),
+            (
+                "a.png; offset 1",
+                "`offset` expects 2 values, got 1",
+                "column 15",
+            ),
+            (
+                "a.png; blend overlay",
+                "Unknown blend mode `overlay`",
+                "column 14",
+            ),
+            (" ; rotate 30", "Expected the path of an image", ""),
+        ] {
+            let error = parse(source).unwrap_err();
+            assert!(error.contains(message), "{source}: {error}");
+            assert!(error.contains(position), "{source}: {error}");
+        }
+    }
+
+    #[test]
+    fn keeps_the_order_of_layers_with_the_same_z() {
+        let layers: Vec<Layer> = ["a.png; z 1", "b.png; z -1", "c.png", "d.png; z 1", "e.png"]
+            .into_iter()
+            .map(|layer| parse(layer).unwrap())
+            .collect();
+
+        let paths: Vec<&str> = in_z_order(&layers)
+            .iter()
+            .map(|layer| layer.path.to_str().unwrap())
+            .collect();
+        assert_eq!(paths, ["b.png", "c.png", "e.png", "a.png", "d.png"]);
+    }
+
+    #[test]
+    fn composites_the_layers_in_z_order() {
+        let layers: Vec<Layer> = [
+            "green.png; z 1; opacity 0.5",
+            "gray.png; z 2; blend multiply; offset 2 0",
+            "red.png; z -1",
+            "blue.png; z 1; opacity 0.5",
+        ]
+        .into_iter()
+        .map(|layer| parse(layer).unwrap())
+        .collect();
+
+        let mut scene = Scene {
+            color: [0, 0, 0, 255],
+            below: Vec::new(),
+            above: Vec::new(),
+        };
+        for layer in in_z_order(&layers) {
+            let color = match layer.path.to_str().unwrap() {
+                "red.png" => [255, 0, 0, 255],
+                "green.png" => [0, 255, 0, 255],
+                "blue.png" => [0, 0, 255, 255],
+                _ => [128, 128, 128, 255],
+            };
+            let placed = Placed {
+                path: layer.path.clone(),
+                image: Some(AnyImage::U8(images::filled((4, 4), 4, color))),
+                dims: (4, 4),
+                layout: Layout {
+                    depth: Depth::Eight,
+                    channels: 4,
+                },
+                matrix: transform::identity(),
+                inverse: transform::identity(),
+                compositing: Compositing::default(),
+            };
+
+            let matrix = layer.matrix(placed.dims);
+            scene.add(
+                placed.transformed(matrix, layer.compositing).unwrap(),
+                layer.z,
+            );
+        }
+        assert_eq!((scene.below.len(), scene.above.len()), (1, 3));
+
+        let mut output: ImageArray<u8> = images::filled((4, 4), 3, scene.color);
+        for placed in scene.below.iter().chain(&scene.above) {
+            placed.draw(&mut output, &sampling(Filter::Nearest));
+        }
+
+        // Red, half covered by green, half covered by blue, and multiplied by gray on the right
+        let close = |pixel: [u8; 3], expected: [u8; 3]| {
+            pixel.iter().zip(expected).all(|(&a, b)| a.abs_diff(b) <= 1)
+        };
+        for (x, expected) in [
+            (0, [64, 64, 128]),
+            (1, [64, 64, 128]),
+            (2, [32, 32, 64]),
+            (3, [32, 32, 64]),
+        ] {
+            for y in 0..4 {
+                let pixel = std::array::from_fn(|channel| output[[y, x, channel]]);
+                assert!(close(pixel, expected), "({x}, {y}): {pixel:?}");
+            }
+        }
+    }
+}
@@ -1,13 +1,33 @@
+mod exact;
+mod explain;
+mod expr;
+mod filter;
 mod images;
+mod layer;
 mod matrix_ext;
-
-use clap::Parser;
+mod pipeline;
+mod plan;
+mod render;
+mod solve;
+mod srgb;
+mod stream;
+mod transform;
+
+use clap::{ArgGroup, Parser};
 use color_eyre::Result;
-use indicatif::{ProgressBar, ProgressStyle};
+use exact::Exact;
+use expr::Number;
+use filter::{Antialias, Blend, Border, Compositing, Edge, Filter};
+use images::{AnyImage, Depth, ImageArray, Layout, Sample};
+use layer::{Layer, Placed, Scene};
 use matrix_ext::MatrixExt;
-use ndarray::{s, Array1, Array2, Array3};
+use ndarray::Array2;
 use owo_colors::OwoColorize as _;
+use pipeline::Pipeline;
+use render::{Bounds, Coverage, Fit, RenderMode, Sampling};
+use solve::Model;
 use std::{fmt::Debug, path::PathBuf, time::Instant};
+use transform::Pivot;
 
 const CHECKMARK: &str = "✓";
 const CROSS: &str = "✗";
@@ -16,6 +36,12 @@ const WARNING: &str = "⚠";
 /// Transform images with the help of matrices
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
+#[command(group(
+    ArgGroup::new("transformation")
+        .required(true)
+        .multiple(true)
+        .args(["matrix", "from", "points", "transform", "transform_file", "rotate", "scale", "shear_x", "shear_y", "reflect_x", "reflect_y"])
+))]
 struct Args {
     /// The name of the input file
     #[arg(short, long)]
@@ -25,37 +51,198 @@ struct Args {
     #[arg(short, long)]
     output: PathBuf,
 
-    /// The transformation matrix to apply to the image (Xx,Xy,Yx,Yy)
-    #[arg(short, long, value_parser = parse_nums::<f32, 4>)]
-    matrix: [f32; 4],
+    /// The transformation matrix to apply to the image (Xx,Xy,Yx,Yy), optionally followed by a
+    /// translation (Xx,Xy,Yx,Yy,Tx,Ty) or as a homogeneous 3x3 matrix (Xx,Xy,Xw,Yx,Yy,Yw,Tx,Ty,Tw),
+    /// which can also describe a perspective transformation
+    #[arg(short, long, value_parser = parse_matrix)]
+    matrix: Option<Array2<f32>>,
+
+    /// Four corners in the input image to map onto the corners given by --to
+    /// (X1,Y1,X2,Y2,X3,Y3,X4,Y4, in pixels from the top left corner)
+    #[arg(long, value_parser = parse_nums::<f32, 8>, requires = "to", conflicts_with_all = ["matrix", "points"])]
+    from: Option<[f32; 8]>,
+
+    /// The four corners in the output image that the corners given by --from end up at
+    /// (X1,Y1,X2,Y2,X3,Y3,X4,Y4, in pixels from the top left corner)
+    #[arg(long, value_parser = parse_nums::<f32, 8>, requires = "from")]
+    to: Option<[f32; 8]>,
+
+    /// A CSV file with point pairs to fit the transformation to (X,Y,X',Y' on every line, in
+    /// pixels from the top left corner)
+    #[arg(short, long, conflicts_with = "matrix")]
+    points: Option<PathBuf>,
+
+    /// The kind of transformation to fit to the point pairs
+    #[arg(long, value_enum, default_value_t, requires = "points")]
+    model: Model,
+
+    /// Reject point pairs further than this many pixels from the fitted transformation (RANSAC)
+    #[arg(long, value_parser = parse_num::<f64>, requires = "points")]
+    ransac: Option<f64>,
+
+    /// A sequence of transformations applied in order, separated by semicolons, e.g.
+    /// "pivot center; rotate 30deg; scale 2 1; translate 10 0; matrix 1,0.5,0,1"
+    #[arg(short, long, value_parser = pipeline::parse, allow_hyphen_values = true)]
+    transform: Option<Pipeline>,
+
+    /// A file with a sequence of transformations like --transform, one per line
+    #[arg(long, conflicts_with = "transform")]
+    transform_file: Option<PathBuf>,
+
+    /// Rotate the image counter-clockwise by this angle (in degrees, or suffixed by deg or rad)
+    #[arg(long, value_parser = transform::parse_angle, allow_hyphen_values = true)]
+    rotate: Option<f32>,
+
+    /// Scale the image by this factor (X,Y or a single value for both axes)
+    #[arg(long, value_parser = parse_scale, allow_hyphen_values = true)]
+    scale: Option<[f32; 2]>,
+
+    /// Shift X by this factor times Y
+    #[arg(long, value_parser = parse_num::<f32>, allow_hyphen_values = true)]
+    shear_x: Option<f32>,
+
+    /// Shift Y by this factor times X
+    #[arg(long, value_parser = parse_num::<f32>, allow_hyphen_values = true)]
+    shear_y: Option<f32>,
+
+    /// Mirror the image across the X axis (upside down)
+    #[arg(long)]
+    reflect_x: bool,
+
+    /// Mirror the image across the Y axis (left to right)
+    #[arg(long)]
+    reflect_y: bool,
+
+    /// The point to rotate, scale, shear and reflect around (origin, center or X,Y in pixels
+    /// from the top left corner)
+    #[arg(long, value_parser = transform::parse_pivot, default_value = "origin")]
+    pivot: Pivot,
+
+    /// Choose the offset and the dimensions of the output image from the transformed image
+    /// (fit-inside scales the image to fit inside --dims)
+    #[arg(long, value_enum, conflicts_with = "offset")]
+    fit: Option<Fit>,
 
     /// The amount to offset the image by (X,Y)
     #[arg(short = 'f', long, value_parser = parse_nums::<isize, 2>)]
     offset: Option<[isize; 2]>,
 
+    /// Explain what the transformation does: its rotation, scale, shear and reflection, singular
+    /// values, eigenvalues and a description in words
+    #[arg(short = 'e', long)]
+    explain: bool,
+
     /// Whether to apply the inverse transformation
     #[arg(short = 'n', long)]
     inverse: bool,
 
-    /// The dimensions of the output image (set to 0 to keep the original dimensions)
+    /// The dimensions of the output image (set to 0 to keep the dimensions of the input image, or
+    /// of the --background-image if there is one)
     #[arg(short, long, value_parser = parse_nums::<usize, 2>)]
     dims: Option<[usize; 2]>,
 
-    /// The color of the background in RGBA format
+    /// The color of the background in RGBA format, which the transformed image is composited over
     #[arg(short, long, value_parser = parse_nums::<u8, 4>)]
     background: Option<[u8; 4]>,
+
+    /// An image to composite the transformed image over, drawn over the --background
+    #[arg(long, conflicts_with = "memory_budget")]
+    background_image: Option<PathBuf>,
+
+    /// A sequence of transformations like --transform applied to the --background-image
+    #[arg(long, value_parser = pipeline::parse, allow_hyphen_values = true, requires = "background_image")]
+    background_transform: Option<Pipeline>,
+
+    /// How the colors of the transformed image are blended with the colors below it
+    #[arg(long, value_enum, default_value_t)]
+    blend: Blend,
+
+    /// The opacity of the transformed image (between 0 and 1)
+    #[arg(long, value_parser = parse_opacity, default_value_t = 1.0)]
+    opacity: f32,
+
+    /// Another image to place onto the output image, followed by its transformations like
+    /// --transform and its settings, e.g. "sticker.png; rotate 30deg; offset 10,20; z 1;
+    /// opacity 0.5; blend multiply" (layers with a negative z are drawn below the transformed
+    /// image, can be given multiple times)
+    #[arg(long, value_parser = layer::parse, allow_hyphen_values = true, conflicts_with = "memory_budget")]
+    layer: Vec<Layer>,
+
+    /// The depth of the samples of the output image (the depth of the input image by default, or
+    /// the closest one the output format can store)
+    #[arg(long, value_enum)]
+    output_depth: Option<Depth>,
+
+    /// Only read the header of the input image and report where the transformed image would end
+    /// up and how much memory rendering it would take, without rendering it
+    #[arg(long)]
+    dry_run: bool,
+
+    /// What to read outside of the input image (transparent by default, which lets the
+    /// --background show through)
+    #[arg(short = 'E', long, value_enum)]
+    edge: Option<Edge>,
+
+    /// How to render the output image
+    #[arg(short = 'r', long, value_enum, default_value_t)]
+    mode: RenderMode,
+
+    /// The filter used to sample the input image (only used in gather mode)
+    #[arg(short = 'F', long, value_enum, default_value_t)]
+    filter: Filter,
+
+    /// How to avoid aliasing when the image is shrunk (only used in gather mode)
+    #[arg(short, long, value_enum, default_value_t)]
+    antialias: Antialias,
+
+    /// The amount of threads to render with (0 uses all CPU cores)
+    #[arg(short = 'j', long, default_value_t = 0)]
+    threads: usize,
+
+    /// Render the output image in bands using at most about this much memory (like 512M or 2G),
+    /// reading only the tiles of the input image that each band needs (PNG, TIFF and binary
+    /// PGM/PPM) and streaming the bands into the output image (PNG, TIFF, PAM and binary
+    /// PGM/PPM)
+    #[arg(long, value_parser = parse_size)]
+    memory_budget: Option<usize>,
+
+    /// The amount of samples along each axis of an output pixel when supersampling
+    #[arg(short, long, default_value_t = 4, value_parser = clap::value_parser!(u16).range(1..))]
+    samples: u16,
+
+    /// Blend the colors of the pixels in linear light instead of sRGB, which keeps edges and fine
+    /// details from getting darker when they're filtered (only used in gather mode)
+    #[arg(long)]
+    linear: bool,
+}
+
+/// Parses a single expression, like `1/3` or `cos(30deg)`
+fn parse_num<T: Number>(s: &str) -> Result<T, String> {
+    let value = expr::evaluate(s).map_err(|e| e.display(s))?;
+
+    T::from_f64(value)
+}
+
+/// Parses comma-separated expressions
+fn parse_list<T: Number>(s: &str) -> Result<Vec<T>, String> {
+    expr::split_top_level(s, ',')
+        .into_iter()
+        .map(|(part, start)| {
+            let value = expr::evaluate(part).map_err(|mut e| {
+                e.span = e.span.start + start..e.span.end + start;
+                e.display(s)
+            })?;
+
+            T::from_f64(value).map_err(|e| format!("{e} ({})", part.trim()))
+        })
+        .collect()
 }
 
 fn parse_nums<T, const N: usize>(s: &str) -> Result<[T; N], String>
 where
-    T: std::str::FromStr + Clone + Debug,
-    <T as std::str::FromStr>::Err: std::fmt::Display,
+    T: Number + Clone + Debug,
 {
-    let values: Vec<T> = s
-        .split(',')
-        .map(|v| v.trim().parse::<T>())
-        .collect::<Result<Vec<_>, _>>()
-        .map_err(|e| e.to_string())?;
+    let values: Vec<T> = parse_list(s)?;
 
     if values.len() != N {
         return Err(format!(
@@ -69,48 +256,395 @@ where
     values.try_into().map_err(|e| format!("{:?}", e))
 }
 
+/// Parses a matrix given column by column into a homogeneous 3x3 matrix
+fn parse_matrix(s: &str) -> Result<Array2<f32>, String> {
+    transform::from_columns(&parse_list(s)?)
+}
+
+fn parse_scale(s: &str) -> Result<[f32; 2], String> {
+    match parse_list(s)?[..] {
+        [scale] => Ok([scale, scale]),
+        [x, y] => Ok([x, y]),
+        ref values => Err(format!(
+            "Expected 1 or 2 elements, got {} ({:?})",
+            values.len(),
+            values
+        )),
+    }
+}
+
+/// Parses an opacity between 0 and 1
+fn parse_opacity(s: &str) -> Result<f32, String> {
+    let value: f32 = parse_num(s)?;
+    if !(0.0..=1.0).contains(&value) {
+        return Err(format!("Expected an opacity between 0 and 1, got {value}"));
+    }
+
+    Ok(value)
+}
+
+/// Parses an amount of bytes, optionally followed by a binary unit (K, M, G or T)
+fn parse_size(s: &str) -> Result<usize, String> {
+    let s = s.trim();
+    let s = s
+        .strip_suffix("iB")
+        .or_else(|| s.strip_suffix('B'))
+        .unwrap_or(s);
+
+    let (number, shift) = match s.chars().last().map(|unit| unit.to_ascii_uppercase()) {
+        Some('K') => (&s[..s.len() - 1], 10),
+        Some('M') => (&s[..s.len() - 1], 20),
+        Some('G') => (&s[..s.len() - 1], 30),
+        Some('T') => (&s[..s.len() - 1], 40),
+        _ => (s, 0),
+    };
+
+    let value: f64 = parse_num(number)?;
+    if value < 0.0 {
+        return Err(format!("Expected a positive size, got {value}"));
+    }
+
+    Ok((value * (1u64 << shift) as f64) as usize)
+}
+
+/// Combines the named transformations into a single matrix, applied in the order: reflect,
+/// scale, shear, rotate
+fn named_transform(args: &Args) -> Array2<f32> {
+    let mut matrix = transform::reflection(args.reflect_x, args.reflect_y);
+
+    if let Some([x, y]) = args.scale {
+        matrix = transform::scale(x, y).dot(&matrix);
+    }
+
+    if args.shear_x.is_some() || args.shear_y.is_some() {
+        let shear = transform::shear(args.shear_x.unwrap_or(0.0), args.shear_y.unwrap_or(0.0));
+        matrix = shear.dot(&matrix);
+    }
+
+    if let Some(angle) = args.rotate {
+        matrix = transform::rotation(angle).dot(&matrix);
+    }
+
+    matrix
+}
+
+/// Converts corners given with Y pointing down into points with Y pointing up
+fn corners(values: [f32; 8], height: usize) -> [[f64; 2]; 4] {
+    std::array::from_fn(|i| {
+        [
+            values[2 * i] as f64,
+            (height - 1) as f64 - values[2 * i + 1] as f64,
+        ]
+    })
+}
+
+/// Renders the output image with `channels` channels from the `input` image in memory into the
+/// `scene`, in gather mode through the `inverse` if there is one, otherwise in scatter mode
+/// through the `matrix`.
+///
+/// Returns the output image and the average amount of input pixels read per output pixel.
+fn render_array<T: Sample>(
+    input: &ImageArray<T>,
+    (out_width, out_height): (usize, usize),
+    channels: usize,
+    scene: &Scene,
+    matrix: &Array2<f32>,
+    inverse: Option<&Array2<f32>>,
+    sampling: &Sampling,
+) -> (AnyImage, f32) {
+    let mut output = images::filled::<T>((out_width, out_height), channels, scene.color);
+    let sampling = sampling.convert::<T>();
+
+    for placed in &scene.below {
+        placed.draw(&mut output, &sampling);
+    }
+
+    let cost = match inverse {
+        Some(inverse) => match Exact::new(matrix).filter(|exact| exact.preserves(&sampling)) {
+            Some(exact) => {
+                println!(
+                    "{} {}",
+                    CHECKMARK.green(),
+                    "The transformation moves whole pixels, copying them exactly".green()
+                );
+
+                exact.render(input, &mut output, &sampling);
+
+                1.0
+            }
+            None => {
+                let pb = render::progress_bar(out_width * out_height);
+                let cost =
+                    render::gather(input, &mut output, 0, out_height, inverse, &sampling, &pb);
+                pb.finish();
+
+                cost
+            }
+        },
+        None => {
+            render::scatter(input, &mut output, matrix, &sampling.compositing);
+
+            let (height, width, _) = input.dim();
+            (width * height) as f32 / (out_width * out_height) as f32
+        }
+    };
+
+    for placed in &scene.above {
+        placed.draw(&mut output, &sampling);
+    }
+
+    (T::wrap(output), cost)
+}
+
 fn main() -> Result<()> {
     color_eyre::install()?;
     let args = Args::parse();
 
+    let edge = args.edge.unwrap_or(Edge::Transparent);
+    let border = match (edge, args.background) {
+        (Edge::Background, Some(background)) => Border {
+            edge,
+            color: background,
+        },
+        (Edge::Background, None) => {
+            eprintln!(
+                "{}",
+                format!("{CROSS} The background edge needs a color given by --background!")
+                    .red()
+                    .bold()
+            );
+            return Ok(());
+        }
+        _ => Border {
+            edge,
+            color: [0; 4],
+        },
+    };
+
+    let sampling = Sampling {
+        filter: args.filter,
+        border,
+        antialias: args.antialias,
+        samples: args.samples as usize,
+        linear: args.linear,
+        compositing: Compositing {
+            blend: args.blend,
+            opacity: args.opacity,
+        },
+    };
+
+    if args.memory_budget.is_some() && args.mode == RenderMode::Scatter {
+        eprintln!(
+            "{}",
+            format!("{CROSS} Rendering with a memory budget only works in gather mode!")
+                .red()
+                .bold()
+        );
+        return Ok(());
+    }
+
     println!(
         "{}",
         format!("Loading image: {}...", args.input.display().yellow()).blue()
     );
 
-    let (array, (width, height)) = images::load_image(&args.input)?;
+    // With a memory budget, the input image is only read in tiles while rendering
+    let mut reader = None;
+    let (array, (width, height), layout) = if args.dry_run {
+        let (dims, layout) = images::read_header(&args.input)?;
+        (None, dims, layout)
+    } else if args.memory_budget.is_some() {
+        let opened = stream::open(&args.input)?;
+        let (dims, layout) = (opened.dimensions(), opened.layout());
+        reader = Some(opened);
+        (None, dims, layout)
+    } else {
+        let (array, dims) = images::load_image(&args.input)?;
+        let layout = array.layout();
+        (Some(array), dims, layout)
+    };
 
     println!(
         "{} {} {}",
         CHECKMARK.green(),
-        "Loaded image with dimensions:".green(),
-        format!("({}, {})", width, height).yellow()
+        if array.is_none() {
+            "Read image header with dimensions:"
+        } else {
+            "Loaded image with dimensions:"
+        }
+        .green(),
+        format!("({}, {}), {}", width, height, layout).yellow()
     );
 
-    let matrix_vec = args.matrix.to_vec();
-    let swapped_matrix = [matrix_vec[0], matrix_vec[2], matrix_vec[1], matrix_vec[3]];
+    let background_image = match &args.background_image {
+        Some(path) => Some(Placed::load(path, args.dry_run)?),
+        None => None,
+    };
+
+    // The output image takes the dimensions of the background image it's composited over
+    let (width_kept, height_kept) = match &background_image {
+        Some(placed) => placed.dims,
+        None => (width, height),
+    };
+    let out_dims = args.dims.unwrap_or([width_kept, height_kept]);
+    let mut out_width = match out_dims[0] {
+        0 => width_kept,
+        _ => out_dims[0],
+    };
+    let mut out_height = match out_dims[1] {
+        0 => height_kept,
+        _ => out_dims[1],
+    };
+
+    let mut matrix = {
+        let matrix = match (&args.matrix, args.from, args.to, &args.points) {
+            (Some(matrix), _, _, _) => matrix.as_standard_layout().into_owned(),
+            (None, Some(from), Some(to), _) => {
+                // The corners are given with Y pointing down, like in image viewers
+                let from = corners(from, height);
+                let to = corners(to, out_height);
+
+                let matrix = solve::homography(&from, &to)?;
+
+                println!(
+                    "{} {}",
+                    CHECKMARK.green(),
+                    "Solved the transformation from the corners".green()
+                );
+
+                matrix
+            }
+            (None, _, _, Some(points)) => {
+                let points = solve::read_points(points)?;
+
+                // The points are given with Y pointing down, like in image viewers
+                let from: Vec<[f64; 2]> = points
+                    .iter()
+                    .map(|p| [p[0], (height - 1) as f64 - p[1]])
+                    .collect();
+                let to: Vec<[f64; 2]> = points
+                    .iter()
+                    .map(|p| [p[2], (out_height - 1) as f64 - p[3]])
+                    .collect();
+
+                let fit = solve::fit(args.model, &from, &to, args.ransac)?;
+
+                println!(
+                    "{} {}",
+                    CHECKMARK.green(),
+                    format!(
+                        "Fitted the {:?} model to {} point pairs:",
+                        args.model,
+                        points.len()
+                    )
+                    .green()
+                );
+
+                for (i, ((point, residual), inlier)) in points
+                    .iter()
+                    .zip(&fit.residuals)
+                    .zip(&fit.inliers)
+                    .enumerate()
+                {
+                    let line = format!(
+                        "  #{:<3} ({}, {}) -> ({}, {}): {:.3} px",
+                        i + 1,
+                        point[0],
+                        point[1],
+                        point[2],
+                        point[3],
+                        residual
+                    );
+
+                    if *inlier {
+                        println!("{}", line);
+                    } else {
+                        println!("{} {}", line.red(), "(outlier)".red());
+                    }
+                }
+
+                println!(
+                    "{} {}",
+                    "RMS error:".blue(),
+                    format!("{:.3} px", fit.rms()).yellow()
+                );
+
+                fit.matrix
+            }
+            _ => transform::identity(),
+        };
+
+        // The pipeline is applied first, then the named transformations, and the matrix last
+        let named = transform::around(
+            &named_transform(&args),
+            args.pivot.position((width, height)),
+        );
+        let matrix = matrix.dot(&named);
+
+        let pipeline = match &args.transform_file {
+            Some(path) => Some(pipeline::parse(&std::fs::read_to_string(path)?)?),
+            None => args.transform.clone(),
+        };
+
+        let mut matrix = match pipeline {
+            Some(pipeline) => matrix.dot(&pipeline.matrix((width, height))),
+            None => matrix,
+        };
 
-    let matrix = {
-        let mut matrix = Array2::from_shape_vec((2, 2), swapped_matrix.to_vec()).unwrap();
         if args.inverse {
-            if matrix.det() == 0.0 {
+            if let Err(e) = matrix.invert() {
                 eprintln!(
                     "{}",
-                    format!("{CROSS} The determinant of the matrix is 0, the transformation is not invertible!")
+                    format!("{CROSS} The transformation is not invertible: {e}!")
                         .red()
                         .bold()
                 );
                 return Ok(());
             }
-            matrix.invert();
         }
+
+        // Translate the result, which also works for perspective transformations
+        if let Some(offset) = args.offset {
+            for col in 0..3 {
+                matrix[[0, col]] += offset[0] as f32 * matrix[[2, col]];
+                matrix[[1, col]] += offset[1] as f32 * matrix[[2, col]];
+            }
+        }
+
         matrix
     };
 
+    let fitted = match args.fit {
+        Some(fit) => {
+            let mut out_dims = (out_width, out_height);
+            let Some(fitted) = fit.apply(&mut matrix, (width, height), &mut out_dims) else {
+                eprintln!(
+                    "{}",
+                    format!("{CROSS} The image crosses the horizon line, so the output image can't be fitted to it!")
+                        .red()
+                        .bold()
+                );
+                return Ok(());
+            };
+
+            (out_width, out_height) = out_dims;
+            Some(fitted)
+        }
+        None => None,
+    };
+
     println!("{}", "Transformation matrix:".blue());
     matrix.print();
 
-    println!("{} {}", "Matrix determinant:".blue(), matrix.det().yellow());
+    println!(
+        "{} {}",
+        "Matrix determinant:".blue(),
+        matrix.det()?.yellow()
+    );
+
+    if args.explain {
+        explain::explain(&matrix);
+    }
 
     if let Some(offset) = &args.offset {
         println!(
@@ -120,17 +654,17 @@ fn main() -> Result<()> {
         );
     }
 
-    let offset = args.offset.unwrap_or([0, 0]);
+    if let Some(((x, y), scale)) = fitted {
+        println!(
+            "{} {}",
+            "Offset:".blue(),
+            format!("({}, {})", x, y).yellow()
+        );
 
-    let out_dims = args.dims.unwrap_or([width, height]);
-    let out_width = match out_dims[0] {
-        0 => width,
-        _ => out_dims[0],
-    };
-    let out_height = match out_dims[1] {
-        0 => height,
-        _ => out_dims[1],
-    };
+        if scale != 1.0 {
+            println!("{} {}", "Scale:".blue(), scale.yellow());
+        }
+    }
 
     println!(
         "{} {}",
@@ -138,94 +672,270 @@ fn main() -> Result<()> {
         format!("({}, {})", out_width, out_height).yellow()
     );
 
-    let mut output = Array3::<u8>::zeros((out_height, out_width, 4));
+    let inverse = match args.mode {
+        RenderMode::Gather => {
+            let mut inverse = matrix.clone();
+            Some(inverse.invert().map(|()| inverse))
+        }
+        RenderMode::Scatter => None,
+    };
+
+    let background = args.background.unwrap_or([0; 4]);
+    // The background image and the layers with a negative z-order are drawn below the
+    // transformed image, from the bottom up
+    let layers = layer::in_z_order(&args.layer);
+
+    let mut scene = Scene {
+        color: background,
+        below: Vec::new(),
+        above: Vec::new(),
+    };
+
+    if let Some(placed) = background_image {
+        let matrix = match &args.background_transform {
+            Some(pipeline) => pipeline.matrix(placed.dims),
+            None => transform::identity(),
+        };
 
-    if let Some(background) = args.background {
-        let background: Array1<u8> = Array1::from_vec(background.to_vec());
-        for y in 0..out_height {
-            for x in 0..out_width {
-                output.slice_mut(s![y, x, ..]).assign(&background);
+        match placed.transformed(matrix, Compositing::default()) {
+            Ok(placed) => scene.below.push(placed),
+            Err(e) => {
+                eprintln!(
+                    "{}",
+                    format!("{CROSS} The background transformation is not invertible: {e}!")
+                        .red()
+                        .bold()
+                );
+                return Ok(());
             }
         }
     }
 
-    let time = Instant::now();
+    for layer in layers {
+        let placed = Placed::load(&layer.path, args.dry_run)?;
+        let matrix = layer.matrix(placed.dims);
+
+        match placed.transformed(matrix, layer.compositing) {
+            Ok(placed) => scene.add(placed, layer.z),
+            Err(e) => {
+                eprintln!(
+                    "{}",
+                    format!(
+                        "{CROSS} The transformation of the layer {} is not invertible: {e}!",
+                        layer.path.display()
+                    )
+                    .red()
+                    .bold()
+                );
+                return Ok(());
+            }
+        }
+    }
 
-    let mut min_x: isize = isize::MAX;
-    let mut max_x: isize = isize::MIN;
-    let mut min_y: isize = isize::MAX;
-    let mut max_y: isize = isize::MIN;
-    let mut cut_off: bool = false;
+    // The background color shows through wherever the images below don't cover it
+    let out_dims = (out_width, out_height);
+    let canvas = scene
+        .below
+        .iter()
+        .fold(images::color_channels(background), |canvas, placed| {
+            placed.channels(canvas, out_dims, &sampling)
+        });
+
+    // The output image keeps the channels of the input image, unless the colors that end up in
+    // it besides the transformed image need more. Copied pixels don't read beyond the pixels
+    // they're copied from, however far the filter reaches.
+    let coverage = match &inverse {
+        Some(Ok(inverse)) => Some(
+            match Exact::new(&matrix).filter(|exact| exact.preserves(&sampling)) {
+                Some(exact) => exact.coverage((width, height), (out_width, out_height)),
+                None => {
+                    Coverage::find(inverse, (width, height), (out_width, out_height), &sampling)
+                }
+            },
+        ),
+        _ => None,
+    };
+    let out_layout = Coverage::layout(coverage, layout, &sampling, canvas);
+    let out_layout = Layout {
+        channels: scene
+            .above
+            .iter()
+            .fold(out_layout.channels, |canvas, placed| {
+                placed.channels(canvas, out_dims, &sampling)
+            }),
+        ..out_layout
+    };
+    let out_layout = Layout {
+        depth: args.output_depth.unwrap_or(match out_layout.depth {
+            // Floating point images can only be written in bands as TIFF images
+            Depth::Float if args.memory_budget.is_some() && !stream::writes_float(&args.output) => {
+                Depth::Sixteen
+            }
+            depth => depth,
+        }),
+        ..out_layout
+    };
 
-    {
-        let pb = ProgressBar::new((height * width) as u64);
-        pb.set_style(
-            ProgressStyle::with_template("{wide_bar} {percent_precise}% ({eta})").unwrap(),
+    if let Err(e) = images::check_format(&args.output, out_layout, args.output_depth) {
+        eprintln!("{}", format!("{CROSS} {e}!").red().bold());
+        return Ok(());
+    }
+
+    if args.dry_run {
+        let offset = match (args.offset, fitted) {
+            (Some([x, y]), _) => (x as f32, y as f32),
+            (_, Some((offset, _))) => offset,
+            _ => (0.0, 0.0),
+        };
+
+        plan::print(
+            &matrix,
+            (width, height),
+            (out_width, out_height),
+            offset,
+            args.mode,
+            (layout, out_layout),
+            &scene,
+            args.memory_budget,
         );
 
-        for y in 0..height {
-            for x in 0..width {
-                let pos = Array2::from_shape_vec((2, 1), vec![x as f32, (height - y - 1) as f32])
-                    .unwrap();
-                let transformed = matrix.dot(&pos);
+        return Ok(());
+    }
 
-                let new_x = transformed[[0, 0]].round() as isize + offset[0];
-                let new_y = transformed[[1, 0]].round() as isize + offset[1];
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(args.threads)
+        .build_global()?;
+
+    let inverse = match inverse {
+        Some(Ok(inverse)) => Some(inverse),
+        Some(Err(e)) => {
+            eprintln!(
+                "{}",
+                format!("{CROSS} The matrix can't be rendered in gather mode, because {e}! Try the scatter mode instead.")
+                    .red()
+                    .bold()
+            );
+            return Ok(());
+        }
+        None => None,
+    };
 
-                min_x = min_x.min(new_x);
-                max_x = max_x.max(new_x);
-                min_y = min_y.min(new_y);
-                max_y = max_y.max(new_y);
+    if inverse.is_none()
+        && (args.filter != Filter::Nearest
+            || args.antialias != Antialias::None
+            || args.edge.is_some()
+            || args.linear)
+    {
+        println!(
+            "{}",
+            format!("{WARNING} Filters, antialiasing, edges and linear light are not supported in scatter mode, ignoring!").red()
+        );
+    }
 
-                let new_y = out_height as isize - new_y - 1;
+    if reader.is_some() {
+        println!(
+            "{}",
+            format!(
+                "Rendering image in bands into: {}...",
+                args.output.display().yellow()
+            )
+            .blue()
+        );
+    }
 
-                if new_x >= 0
-                    && new_x < out_width as isize
-                    && new_y >= 0
-                    && new_y < out_height as isize
-                {
-                    output
-                        .slice_mut(s![new_y as usize, new_x as usize, ..])
-                        .assign(&array.slice(s![y, x, ..]));
-                } else {
-                    cut_off = true;
-                }
+    let time = Instant::now();
 
-                pb.inc(1);
-            }
+    // Rendering with a memory budget writes the image while rendering, in the returned layout
+    let (cost, output, written) = match (&mut reader, array) {
+        (Some(reader), _) => {
+            let output = stream::Output {
+                path: &args.output,
+                dimensions: (out_width, out_height),
+                layout: out_layout,
+                background,
+            };
+            let inverse = inverse
+                .as_ref()
+                .expect("rendering with a memory budget only works in gather mode");
+            let budget = args
+                .memory_budget
+                .expect("the image is only read in tiles with a memory budget");
+
+            let (cost, layout) =
+                stream::render(reader.as_mut(), &output, inverse, &sampling, budget)?;
+            (cost, None, Some(layout))
         }
-
-        pb.finish();
-    }
+        (None, Some(array)) => {
+            let out_dims = (out_width, out_height);
+            let channels = out_layout.channels;
+            let inverse = inverse.as_ref();
+
+            let (output, cost) = match &array {
+                AnyImage::U8(array) => render_array(
+                    array, out_dims, channels, &scene, &matrix, inverse, &sampling,
+                ),
+                AnyImage::U16(array) => render_array(
+                    array, out_dims, channels, &scene, &matrix, inverse, &sampling,
+                ),
+                AnyImage::F32(array) => render_array(
+                    array, out_dims, channels, &scene, &matrix, inverse, &sampling,
+                ),
+            };
+            (cost, Some(output), None)
+        }
+        (None, None) => {
+            unreachable!("the image is only left unloaded in a dry run or with a memory budget")
+        }
+    };
 
     println!(
-        "{} {} {:?}",
+        "{} {} {:?} {}",
         format!("{CHECKMARK} Done!").green(),
         "Took:".blue(),
-        time.elapsed().yellow()
-    );
-
-    println!(
-        "{} {}",
-        "Actual bounding box:".blue(),
-        format!("({}, {}) - ({}, {})", min_x, min_y, max_x, max_y).yellow()
+        time.elapsed().yellow(),
+        format!("({cost:.1} input pixels read per output pixel)").blue()
     );
 
-    if cut_off {
-        println!("{}", format!("{WARNING} Some pixels were cut off!").red());
+    match Bounds::project(&matrix, (width, height)) {
+        Some(bounds) => {
+            println!(
+                "{} {}",
+                "Actual bounding box:".blue(),
+                format!(
+                    "({}, {}) - ({}, {})",
+                    bounds.min_x, bounds.min_y, bounds.max_x, bounds.max_y
+                )
+                .yellow()
+            );
+
+            if bounds.exceeds((out_width, out_height)) {
+                println!("{}", format!("{WARNING} Some pixels were cut off!").red());
+            }
+        }
+        None => {
+            println!(
+                "{}",
+                format!("{WARNING} The image crosses the horizon line, so it's unbounded and some pixels were cut off!").red()
+            );
+        }
     }
 
-    println!(
-        "{}",
-        format!("Saving image: {}...", args.output.display().yellow()).blue()
-    );
+    let layout = match output {
+        Some(output) => {
+            println!(
+                "{}",
+                format!("Saving image: {}...", args.output.display().yellow()).blue()
+            );
 
-    images::save_image(output, &args.output)?;
+            images::save_image(output, &args.output, args.output_depth)?
+        }
+        None => written.expect("images that aren't kept in memory are written while rendering"),
+    };
 
     println!(
         "{} {}",
         format!("{CHECKMARK} Saved image with dimensions:").green(),
-        format!("({}, {})", out_width, out_height).yellow()
+        format!("({}, {}), {}", out_width, out_height, layout).yellow()
     );
 
     Ok(())
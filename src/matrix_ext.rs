@@ -1,9 +1,39 @@
-use ndarray::Array2;
+use std::fmt;
+
+use ndarray::{Array1, Array2, ArrayView1, Axis};
+
+/// The largest condition number for which an inverse is still accurate in single precision
+const MAX_CONDITION: f64 = 1.0 / f32::EPSILON as f64;
+
+/// Why a matrix can't be inverted
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum MatrixError {
+    NotSquare { rows: usize, cols: usize },
+    Singular,
+    IllConditioned { condition: f64 },
+}
+
+impl fmt::Display for MatrixError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MatrixError::NotSquare { rows, cols } => {
+                write!(f, "the matrix isn't square ({rows}x{cols})")
+            }
+            MatrixError::Singular => write!(f, "the matrix is singular (its determinant is 0)"),
+            MatrixError::IllConditioned { condition } => write!(
+                f,
+                "the matrix is ill-conditioned (condition number {condition:.3e}), so its inverse would be inaccurate"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MatrixError {}
 
 pub(crate) trait MatrixExt {
     fn print(&self);
-    fn invert(&mut self);
-    fn det(&self) -> f32;
+    fn invert(&mut self) -> Result<(), MatrixError>;
+    fn det(&self) -> Result<f32, MatrixError>;
 }
 
 impl MatrixExt for Array2<f32> {
@@ -17,30 +47,259 @@ impl MatrixExt for Array2<f32> {
         }
     }
 
-    fn invert(&mut self) {
-        if self.dim() != (2, 2) {
-            unimplemented!("Matrix inversion is only implemented for 2x2 matrices");
+    fn invert(&mut self) -> Result<(), MatrixError> {
+        let matrix = self.mapv(|v| v as f64);
+        let inverse = Lu::new(&matrix)?.inverse()?;
+
+        let condition = condition(&matrix, &inverse);
+        if condition > MAX_CONDITION || condition.is_nan() {
+            return Err(MatrixError::IllConditioned { condition });
+        }
+
+        self.assign(&inverse.mapv(|v| v as f32));
+
+        Ok(())
+    }
+
+    fn det(&self) -> Result<f32, MatrixError> {
+        Ok(Lu::new(&self.mapv(|v| v as f64))?.det() as f32)
+    }
+}
+
+/// The condition number of the matrix after scaling its rows and columns to similar magnitudes.
+///
+/// That's what limits the accuracy of the inverse, and unlike the plain condition number, it isn't
+/// inflated by mixing units, like a translation by thousands of pixels next to a rotation.
+fn condition(matrix: &Array2<f64>, inverse: &Array2<f64>) -> f64 {
+    // Scaling by powers of two is exact
+    let scale = |max: f64| {
+        if max > 0.0 {
+            (-max.log2().round()).exp2()
+        } else {
+            1.0
+        }
+    };
+    let max_abs = |values: ArrayView1<f64>| values.fold(0.0, |max: f64, v| max.max(v.abs()));
+
+    let rows = Array1::from_iter(matrix.rows().into_iter().map(|row| scale(max_abs(row))));
+    let scaled = matrix * &rows.view().insert_axis(Axis(1));
+    let cols = Array1::from_iter(scaled.columns().into_iter().map(|col| scale(max_abs(col))));
+    let scaled = scaled * &cols;
+
+    // The inverse of D_r * A * D_c is D_c^-1 * A^-1 * D_r^-1
+    let scaled_inverse = inverse / &cols.view().insert_axis(Axis(1)) / &rows;
+
+    norm_1(&scaled) * norm_1(&scaled_inverse)
+}
+
+/// The maximum absolute column sum of the matrix
+fn norm_1(matrix: &Array2<f64>) -> f64 {
+    matrix
+        .columns()
+        .into_iter()
+        .map(|column| column.iter().map(|v| v.abs()).sum::<f64>())
+        .fold(0.0, f64::max)
+}
+
+/// The LU decomposition of a square matrix with partial pivoting, such that `P * A = L * U`
+pub(crate) struct Lu {
+    /// L below the diagonal (with an implicit unit diagonal) and U on and above it
+    lu: Array2<f64>,
+
+    /// The row of the original matrix that ended up at each row
+    permutation: Vec<usize>,
+
+    /// Whether an odd amount of rows was swapped, flipping the sign of the determinant
+    odd: bool,
+
+    /// Pivots smaller than this are treated as zero
+    tolerance: f64,
+}
+
+impl Lu {
+    pub(crate) fn new(matrix: &Array2<f64>) -> Result<Self, MatrixError> {
+        let (rows, cols) = matrix.dim();
+        if rows != cols {
+            return Err(MatrixError::NotSquare { rows, cols });
+        }
+
+        let n = rows;
+        let mut lu = matrix.clone();
+        let mut permutation: Vec<usize> = (0..n).collect();
+        let mut odd = false;
+
+        let scale = matrix.iter().fold(0.0, |max: f64, v| max.max(v.abs()));
+        let tolerance = n as f64 * f64::EPSILON * scale;
+
+        for col in 0..n {
+            let pivot = (col..n)
+                .max_by(|&i, &j| lu[[i, col]].abs().total_cmp(&lu[[j, col]].abs()))
+                .unwrap();
+
+            if pivot != col {
+                for k in 0..n {
+                    lu.swap([pivot, k], [col, k]);
+                }
+                permutation.swap(pivot, col);
+                odd = !odd;
+            }
+
+            // Leave the column alone if it's already eliminated, the matrix is singular then
+            if lu[[col, col]].abs() <= tolerance {
+                continue;
+            }
+
+            for row in col + 1..n {
+                let factor = lu[[row, col]] / lu[[col, col]];
+                lu[[row, col]] = factor;
+                for k in col + 1..n {
+                    lu[[row, k]] -= factor * lu[[col, k]];
+                }
+            }
+        }
+
+        Ok(Lu {
+            lu,
+            permutation,
+            odd,
+            tolerance,
+        })
+    }
+
+    pub(crate) fn det(&self) -> f64 {
+        let det: f64 = self.lu.diag().iter().product();
+        if self.odd {
+            -det
+        } else {
+            det
+        }
+    }
+
+    /// Solves `A * x = b`
+    pub(crate) fn solve(&self, b: &Array1<f64>) -> Result<Array1<f64>, MatrixError> {
+        let n = b.len();
+
+        if self
+            .lu
+            .diag()
+            .iter()
+            .any(|pivot| pivot.abs() <= self.tolerance)
+        {
+            return Err(MatrixError::Singular);
         }
 
-        let det = self.det();
-        let inv_det = 1.0 / det;
+        // Forward substitution with L
+        let mut x: Array1<f64> = self.permutation.iter().map(|&i| b[i]).collect();
+        for row in 0..n {
+            for k in 0..row {
+                x[row] -= self.lu[[row, k]] * x[k];
+            }
+        }
+
+        // Back substitution with U
+        for row in (0..n).rev() {
+            for k in row + 1..n {
+                x[row] -= self.lu[[row, k]] * x[k];
+            }
+            x[row] /= self.lu[[row, row]];
+        }
 
-        self[[0, 0]] *= inv_det;
-        self[[0, 1]] *= -inv_det;
-        self[[1, 0]] *= -inv_det;
-        self[[1, 1]] *= inv_det;
+        Ok(x)
     }
 
-    fn det(&self) -> f32 {
-        if self.dim() != (2, 2) {
-            unimplemented!("Matrix determinant is only implemented for 2x2 matrices");
+    pub(crate) fn inverse(&self) -> Result<Array2<f64>, MatrixError> {
+        let n = self.permutation.len();
+        let mut inverse = Array2::zeros((n, n));
+
+        for col in 0..n {
+            let mut unit = Array1::zeros(n);
+            unit[col] = 1.0;
+
+            inverse.column_mut(col).assign(&self.solve(&unit)?);
         }
 
-        let a = self[[1, 1]];
-        let b = self[[1, 0]];
-        let c = self[[0, 1]];
-        let d = self[[0, 0]];
+        Ok(inverse)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::array;
+
+    use super::*;
+    use crate::transform;
+
+    fn assert_close(actual: &Array2<f32>, expected: &Array2<f32>) {
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < 1e-5, "{actual} != {expected}");
+        }
+    }
+
+    #[test]
+    fn inverts_known_matrices() {
+        let mut matrix = array![[2.0, 0.0, 3.0], [0.0, 4.0, 0.0], [0.0, 0.0, 1.0]];
+        matrix.invert().unwrap();
+        assert_close(
+            &matrix,
+            &array![[0.5, 0.0, -1.5], [0.0, 0.25, 0.0], [0.0, 0.0, 1.0]],
+        );
+
+        // Needs pivoting, the first pivot is 0
+        let mut matrix = array![[0.0, 1.0, 5.0], [1.0, 0.0, -2.0], [0.0, 0.0, 1.0]];
+        matrix.invert().unwrap();
+        assert_close(
+            &matrix,
+            &array![[0.0, 1.0, 2.0], [1.0, 0.0, -5.0], [0.0, 0.0, 1.0]],
+        );
+    }
+
+    #[test]
+    fn determinants_follow_row_swaps() {
+        let swapped = array![[0.0, 1.0, 0.0], [1.0, 0.0, 0.0], [0.0, 0.0, 1.0]];
+        assert_eq!(swapped.det().unwrap(), -1.0);
+
+        let matrix = array![[2.0, 1.0, 0.0], [1.0, 3.0, 0.0], [0.0, 0.0, 2.0]];
+        assert!((matrix.det().unwrap() - 10.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn rejects_singular_matrices() {
+        let mut matrix = array![[1.0, 2.0, 0.0], [2.0, 4.0, 0.0], [0.0, 0.0, 1.0]];
+        assert_eq!(matrix.det().unwrap(), 0.0);
+        assert_eq!(matrix.invert(), Err(MatrixError::Singular));
+
+        let mut zero = Array2::<f32>::zeros((3, 3));
+        assert_eq!(zero.invert(), Err(MatrixError::Singular));
+
+        let mut wide = Array2::<f32>::zeros((2, 3));
+        assert_eq!(
+            wide.invert(),
+            Err(MatrixError::NotSquare { rows: 2, cols: 3 })
+        );
+    }
+
+    #[test]
+    fn rejects_ill_conditioned_matrices() {
+        let nearly = 1.0 + 2.0 * f32::EPSILON;
+        let mut matrix = array![[1.0, 1.0, 0.0], [1.0, nearly, 0.0], [0.0, 0.0, 1.0]];
+        assert!(matches!(
+            matrix.invert(),
+            Err(MatrixError::IllConditioned { .. })
+        ));
+    }
+
+    #[test]
+    fn equilibrates_before_measuring_the_condition() {
+        // Rotating around the center of an 8K image mixes translations in the thousands with
+        // entries below 1, which only the plain condition number is inflated by
+        let rotation = transform::around(&transform::rotation(0.5), (3840.0, 2160.0));
+        let matrix = rotation.mapv(|v| v as f64);
+        let inverse = Lu::new(&matrix).unwrap().inverse().unwrap();
+
+        assert!(norm_1(&matrix) * norm_1(&inverse) > 1e6);
+        assert!(condition(&matrix, &inverse) < 10.0);
 
-        a * d - b * c
+        let mut rotation = rotation;
+        assert!(rotation.invert().is_ok());
     }
 }
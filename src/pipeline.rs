@@ -0,0 +1,371 @@
+use std::{fmt, ops::Range};
+
+use ndarray::Array2;
+
+use crate::{
+    expr::{self, ExprError},
+    transform::{self, Pivot},
+};
+
+/// A single operation of a transformation pipeline
+#[derive(Clone, Debug)]
+enum Step {
+    Rotate(f32),
+    Scale(f32, f32),
+    Shear(f32, f32),
+    Reflect { across_x: bool, across_y: bool },
+    Translate(f32, f32),
+    Matrix(Array2<f32>),
+    Pivot(Pivot),
+}
+
+/// A sequence of transformations, applied in order, like
+/// `rotate 30deg; scale 2 1; translate 10 0; matrix 1,0.5,0,1`
+#[derive(Clone, Debug)]
+pub(crate) struct Pipeline(Vec<Step>);
+
+impl Pipeline {
+    /// Composes all steps into a single matrix, for an input image of the given size
+    pub(crate) fn matrix(&self, dims: (usize, usize)) -> Array2<f32> {
+        let mut pivot = Pivot::Origin;
+        let mut matrix = transform::identity();
+
+        for step in &self.0 {
+            // Translations and raw matrices are not affected by the pivot
+            let step = match *step {
+                Step::Rotate(angle) => transform::rotation(angle),
+                Step::Scale(x, y) => transform::scale(x, y),
+                Step::Shear(x, y) => transform::shear(x, y),
+                Step::Reflect { across_x, across_y } => transform::reflection(across_x, across_y),
+                Step::Translate(x, y) => {
+                    matrix = transform::translation(x, y).dot(&matrix);
+                    continue;
+                }
+                Step::Matrix(ref step) => {
+                    matrix = step.dot(&matrix);
+                    continue;
+                }
+                Step::Pivot(new_pivot) => {
+                    pivot = new_pivot;
+                    continue;
+                }
+            };
+
+            matrix = transform::around(&step, pivot.position(dims)).dot(&matrix);
+        }
+
+        matrix
+    }
+}
+
+/// An error in the description of a pipeline, pointing at the offending part of it
+#[derive(Debug)]
+pub(crate) struct PipelineError {
+    source: String,
+    span: Range<usize>,
+    message: String,
+}
+
+impl fmt::Display for PipelineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let line_start = self.source[..self.span.start]
+            .rfind('\n')
+            .map_or(0, |i| i + 1);
+        let line_end = self.source[self.span.start..]
+            .find('\n')
+            .map_or(self.source.len(), |i| self.span.start + i);
+
+        let line_number = self.source[..line_start].matches('\n').count() + 1;
+        let column = self.source[line_start..self.span.start].chars().count() + 1;
+        let width = self.source[self.span.clone()].chars().count().max(1);
+
+        writeln!(
+            f,
+            "{} (line {}, column {})",
+            self.message, line_number, column
+        )?;
+        writeln!(f, "  {}", &self.source[line_start..line_end])?;
+        write!(f, "  {}{}", " ".repeat(column - 1), "^".repeat(width))
+    }
+}
+
+impl std::error::Error for PipelineError {}
+
+/// A word of the pipeline description, along with its position
+struct Token<'a> {
+    text: &'a str,
+    span: Range<usize>,
+}
+
+/// Parses a pipeline description. Steps are separated by semicolons or new lines, their
+/// arguments by spaces or commas, and `#` starts a comment.
+pub(crate) fn parse(source: &str) -> Result<Pipeline, PipelineError> {
+    parse_with(source, 0, |_, _| None)
+}
+
+/// Parses the pipeline description in `source` from `start` on like [`parse`], handing
+/// statements that aren't operations to `other` with their arguments. It returns `None` for
+/// statements it doesn't know either.
+pub(crate) fn parse_with(
+    source: &str,
+    start: usize,
+    mut other: impl FnMut(&str, &[&str]) -> Option<Result<(), String>>,
+) -> Result<Pipeline, PipelineError> {
+    let error = |span: Range<usize>, message: String| PipelineError {
+        source: source.to_string(),
+        span,
+        message,
+    };
+
+    // Expression errors point inside of the argument
+    let expr_error = |arg: &Token, e: ExprError| {
+        error(
+            arg.span.start + e.span.start..arg.span.start + e.span.end,
+            e.message,
+        )
+    };
+
+    let mut steps = Vec::new();
+
+    for statement in statements(source, start) {
+        let Some((name, args)) = statement.split_first() else {
+            continue;
+        };
+
+        let args_span = match (args.first(), args.last()) {
+            (Some(first), Some(last)) => first.span.start..last.span.end,
+            _ => name.span.end..name.span.end,
+        };
+
+        let numbers = |count: &[usize]| -> Result<Vec<f32>, PipelineError> {
+            let values = args
+                .iter()
+                .map(|arg| {
+                    expr::evaluate(arg.text)
+                        .map(|value| value as f32)
+                        .map_err(|e| expr_error(arg, e))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+
+            if !count.contains(&values.len()) {
+                let expected = count
+                    .iter()
+                    .map(|c| c.to_string())
+                    .collect::<Vec<_>>()
+                    .join(" or ");
+
+                return Err(error(
+                    args_span.clone(),
+                    format!(
+                        "`{}` expects {} values, got {}",
+                        name.text,
+                        expected,
+                        values.len()
+                    ),
+                ));
+            }
+
+            Ok(values)
+        };
+
+        let step = match name.text {
+            "rotate" => {
+                let [angle] = args else {
+                    return Err(error(
+                        args_span,
+                        format!("`rotate` expects 1 angle, got {} values", args.len()),
+                    ));
+                };
+
+                Step::Rotate(
+                    expr::evaluate_angle(angle.text).map_err(|e| expr_error(angle, e))? as f32,
+                )
+            }
+            "scale" => match numbers(&[1, 2])?[..] {
+                [scale] => Step::Scale(scale, scale),
+                [x, y] => Step::Scale(x, y),
+                _ => unreachable!(),
+            },
+            "shear" => match numbers(&[2])?[..] {
+                [x, y] => Step::Shear(x, y),
+                _ => unreachable!(),
+            },
+            "shear-x" => Step::Shear(numbers(&[1])?[0], 0.0),
+            "shear-y" => Step::Shear(0.0, numbers(&[1])?[0]),
+            "reflect-x" | "reflect-y" => {
+                numbers(&[0])?;
+                Step::Reflect {
+                    across_x: name.text == "reflect-x",
+                    across_y: name.text == "reflect-y",
+                }
+            }
+            "translate" => match numbers(&[2])?[..] {
+                [x, y] => Step::Translate(x, y),
+                _ => unreachable!(),
+            },
+            "matrix" => Step::Matrix(
+                transform::from_columns(&numbers(&[4, 6, 9])?).map_err(|e| error(args_span, e))?,
+            ),
+            "pivot" => match args {
+                [arg] if arg.text == "origin" => Step::Pivot(Pivot::Origin),
+                [arg] if arg.text == "center" => Step::Pivot(Pivot::Center),
+                _ => match numbers(&[2])?[..] {
+                    [x, y] => Step::Pivot(Pivot::Point(x, y)),
+                    _ => unreachable!(),
+                },
+            },
+            _ => {
+                let texts: Vec<&str> = args.iter().map(|arg| arg.text).collect();
+                match other(name.text, &texts) {
+                    Some(Ok(())) => continue,
+                    Some(Err(message)) => return Err(error(args_span, message)),
+                    None => {}
+                }
+
+                return Err(error(
+                    name.span.clone(),
+                    format!(
+                        "Unknown operation `{}` (expected rotate, scale, shear, shear-x, shear-y, reflect-x, reflect-y, translate, matrix or pivot)",
+                        name.text
+                    ),
+                ));
+            }
+        };
+
+        steps.push(step);
+    }
+
+    Ok(Pipeline(steps))
+}
+
+/// Splits the source from `start` on into statements made of tokens, skipping comments.
+///
+/// Spaces and commas inside parentheses don't split tokens, so arguments can be expressions
+/// like `atan2(1, 2)`.
+fn statements(source: &str, start: usize) -> Vec<Vec<Token<'_>>> {
+    let mut statements = vec![Vec::new()];
+    let mut token_start = None;
+    let mut in_comment = false;
+    let mut depth = 0;
+
+    let chars = source[start..]
+        .char_indices()
+        .map(|(i, c)| (start + i, c))
+        .chain(std::iter::once((source.len(), '\n')));
+
+    for (i, c) in chars {
+        let separator =
+            c == '\n' || c == '#' || (depth == 0 && (c.is_whitespace() || c == ',' || c == ';'));
+
+        if separator || in_comment {
+            if let Some(start) = token_start.take() {
+                statements.last_mut().unwrap().push(Token {
+                    text: &source[start..i],
+                    span: start..i,
+                });
+            }
+        } else if token_start.is_none() {
+            token_start = Some(i);
+        }
+
+        match c {
+            _ if in_comment && c != '\n' => {}
+            '#' => in_comment = true,
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            '\n' | ';' if separator => {
+                in_comment = false;
+                depth = 0;
+                statements.push(Vec::new());
+            }
+            _ => {}
+        }
+    }
+
+    statements
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parses `source`, expecting it to fail, and returns the failing part along with the error
+    fn failure(source: &str) -> (&str, PipelineError) {
+        let error = parse(source).unwrap_err();
+        (&source[error.span.clone()], error)
+    }
+
+    #[test]
+    fn composes_steps_in_order() {
+        let scale = transform::scale(2.0, 2.0);
+        let translation = transform::translation(10.0, 0.0);
+
+        let matrix = parse("scale 2; translate 10 0").unwrap().matrix((5, 3));
+        assert_eq!(matrix, translation.dot(&scale));
+
+        let matrix = parse("translate 10, 0\nscale 2").unwrap().matrix((5, 3));
+        assert_eq!(matrix, scale.dot(&translation));
+
+        let matrix = parse("matrix 1,0.5,0,1; reflect-x").unwrap().matrix((5, 3));
+        let columns = transform::from_columns(&[1.0, 0.5, 0.0, 1.0]).unwrap();
+        assert_eq!(matrix, transform::reflection(true, false).dot(&columns));
+    }
+
+    #[test]
+    fn transforms_around_the_pivot() {
+        let matrix = parse("pivot center; rotate 90deg # a quarter turn\ntranslate 1 1")
+            .unwrap()
+            .matrix((5, 3));
+        let center = ndarray::arr1(&[2.0, 1.0, 1.0]);
+
+        // The pivot stays in place, and only the translation moves it
+        assert_eq!(matrix.dot(&center), ndarray::arr1(&[3.0, 2.0, 1.0]));
+    }
+
+    #[test]
+    fn points_at_the_offending_token() {
+        for (source, part, message) in [
+            ("rotate 30deg; scal 2", "scal", "Unknown operation `scal`"),
+            (
+                "rotate 30; translate 1 2 3",
+                "1 2 3",
+                "`translate` expects 2 values, got 3",
+            ),
+            (
+                "translate 10 cos(1, 2)",
+                "cos(1, 2)",
+                "`cos` takes 1 argument, got 2",
+            ),
+            ("scale 2*x", "x", "Unknown constant `x`"),
+            ("rotate", "", "`rotate` expects 1 angle, got 0 values"),
+        ] {
+            let (found, error) = failure(source);
+            assert_eq!(found, part, "{source}");
+            assert!(error.message.starts_with(message), "{}", error.message);
+        }
+
+        let (_, error) = failure("rotate 30\nscale 2 3 4");
+        assert_eq!(
+            error.to_string(),
+            "`scale` expects 1 or 2 values, got 3 (line 2, column 7)\n  scale 2 3 4\n        ^^^^^"
+        );
+    }
+
+    #[test]
+    fn points_into_the_whole_source() {
+        let source = "image.png; z 1; rotat 30";
+        let mut z = None;
+
+        let error = parse_with(source, 10, |name, args| {
+            (name == "z").then(|| {
+                z = Some(args.join(" "));
+                Ok(())
+            })
+        })
+        .unwrap_err();
+
+        assert_eq!(z.as_deref(), Some("1"));
+        assert_eq!(&source[error.span.clone()], "rotat");
+        assert!(error.to_string().contains("(line 1, column 17)"));
+    }
+}
@@ -0,0 +1,382 @@
+use ndarray::Array2;
+use owo_colors::OwoColorize as _;
+
+use crate::{
+    explain,
+    images::Layout,
+    layer::Scene,
+    matrix_ext::MatrixExt,
+    render::{self, Bounds, RenderMode},
+    WARNING,
+};
+
+/// Formats an amount of bytes with a binary unit
+pub(crate) fn bytes(amount: u128) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut value = amount as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{amount} {}", UNITS[0])
+    } else {
+        format!("{value:.2} {}", UNITS[unit])
+    }
+}
+
+/// The `--offset` and `--dims` that fit the output image exactly to the `bounds` of the
+/// transformed image, like `--fit crop`, given the `offset` already applied to the matrix
+fn suggest(bounds: &Bounds, offset: (f32, f32)) -> ((f32, f32), (usize, usize)) {
+    (
+        (
+            (offset.0 - bounds.min_x as f32).round(),
+            (offset.1 - bounds.min_y as f32).round(),
+        ),
+        (
+            (bounds.max_x - bounds.min_x + 1) as usize,
+            (bounds.max_y - bounds.min_y + 1) as usize,
+        ),
+    )
+}
+
+/// The memory rendering in memory takes, in bytes
+struct Memory {
+    /// The input image
+    input: u128,
+
+    /// The layers and the background image
+    images: u128,
+
+    /// The output image
+    output: u128,
+
+    /// Everything at once, at the peak
+    peak: u128,
+}
+
+/// Estimates the memory rendering a `width`x`height` image in memory into an
+/// `out_width`x`out_height` image with the `scene` takes, with the layouts of the input and the
+/// output image
+fn memory(
+    (width, height): (usize, usize),
+    (out_width, out_height): (usize, usize),
+    (layout, out_layout): (Layout, Layout),
+    scene: &Scene,
+) -> Memory {
+    // The layers and the background image stay loaded while rendering, and each is drawn from a
+    // copy in the depth of the input image. Saving then converts the output image into a copy in
+    // the layout it's saved in.
+    let (images, drawn) = scene
+        .below
+        .iter()
+        .chain(&scene.above)
+        .map(|placed| placed.memory(layout.depth))
+        .fold((0, 0), |(images, drawn), (image, copy)| {
+            (images + image, drawn.max(copy))
+        });
+    let input = width as u128 * height as u128 * layout.bytes_per_pixel() as u128;
+    let rendered = out_width as u128 * out_height as u128;
+    let output = rendered * out_layout.channels as u128 * layout.depth.bytes() as u128;
+    let saved = rendered * out_layout.bytes_per_pixel() as u128;
+    let peak = input + images + output + drawn.max(saved);
+
+    Memory {
+        input,
+        images,
+        output,
+        peak,
+    }
+}
+
+/// Prints the geometry of the transformation of a `width`x`height` image without rendering it:
+/// where the corners end up, the bounding box, the offset and dimensions that would fit it, how
+/// much the image is scaled and how much memory rendering would take.
+///
+/// `offset` is the translation already applied to the matrix through `--offset` or `--fit`, the
+/// layouts are the ones of the input and the output image, the `scene` holds the layers and the
+/// background image drawn besides it and `budget` is the memory budget to render within.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn print(
+    matrix: &Array2<f32>,
+    (width, height): (usize, usize),
+    (out_width, out_height): (usize, usize),
+    offset: (f32, f32),
+    mode: RenderMode,
+    (layout, out_layout): (Layout, Layout),
+    scene: &Scene,
+    budget: Option<usize>,
+) {
+    let (right, top) = ((width - 1) as f32, (height - 1) as f32);
+
+    // Named and ordered like in image viewers, but positioned with Y pointing up like the
+    // bounding box
+    let corners = [
+        ("Top left:", 0.0, top),
+        ("Top right:", right, top),
+        ("Bottom right:", right, 0.0),
+        ("Bottom left:", 0.0, 0.0),
+    ];
+
+    println!("{}", "Transformed corners:".blue());
+    for (name, x, y) in corners {
+        match render::transform_point(matrix, x, y) {
+            Some((new_x, new_y)) => println!(
+                "  {} {}",
+                name.blue(),
+                format!("({:.2}, {:.2})", new_x, new_y).yellow()
+            ),
+            None => println!("  {} {}", name.blue(), "behind the horizon line".red()),
+        }
+    }
+
+    // How much the image is stretched around each corner, which only differs for perspective
+    // transformations
+    let (min_scale, max_scale) = corners
+        .iter()
+        .filter(|(_, x, y)| render::transform_point(matrix, *x, *y).is_some())
+        .map(|&(_, x, y)| {
+            let jacobian = render::jacobian(matrix, x, y).map(|row| row.map(|v| v as f64));
+            explain::singular_values(jacobian)
+        })
+        .fold((f64::INFINITY, 0.0_f64), |(min, max), (most, least)| {
+            (min.min(least), max.max(most))
+        });
+
+    if min_scale.is_finite() {
+        println!(
+            "{} {}",
+            "Scale factors:".blue(),
+            format!("between {:.4} and {:.4}", min_scale, max_scale).yellow()
+        );
+    }
+
+    match Bounds::project(matrix, (width, height)) {
+        Some(bounds) => {
+            println!(
+                "{} {}",
+                "Bounding box:".blue(),
+                format!(
+                    "({}, {}) - ({}, {})",
+                    bounds.min_x, bounds.min_y, bounds.max_x, bounds.max_y
+                )
+                .yellow()
+            );
+
+            let (suggested_offset, suggested_dims) = suggest(&bounds, offset);
+
+            println!(
+                "{} {}",
+                "Suggested options:".blue(),
+                format!(
+                    "--offset={},{} --dims {},{} (or --fit crop)",
+                    suggested_offset.0, suggested_offset.1, suggested_dims.0, suggested_dims.1
+                )
+                .yellow()
+            );
+
+            if bounds.exceeds((out_width, out_height)) {
+                println!(
+                    "{}",
+                    format!("{WARNING} Some pixels would be cut off!").red()
+                );
+            }
+        }
+        None => {
+            println!(
+                "{}",
+                format!("{WARNING} The image crosses the horizon line, so it's unbounded and some pixels would be cut off!").red()
+            );
+        }
+    }
+
+    if mode == RenderMode::Gather {
+        if let Err(e) = matrix.clone().invert() {
+            println!(
+                "{}",
+                format!("{WARNING} The matrix can't be rendered in gather mode, because {e}!")
+                    .red()
+            );
+        }
+    }
+
+    // With a memory budget, the bands are planned to fit into it while rendering
+    if let Some(budget) = budget {
+        println!(
+            "{} {}",
+            "Estimated memory:".blue(),
+            format!("at most {} within the memory budget", bytes(budget as u128)).yellow()
+        );
+        return;
+    }
+
+    let Memory {
+        input,
+        images,
+        output,
+        peak,
+    } = memory(
+        (width, height),
+        (out_width, out_height),
+        (layout, out_layout),
+        scene,
+    );
+
+    let layers = if images > 0 {
+        format!(
+            "{} for the layers and the background image, ",
+            bytes(images)
+        )
+    } else {
+        String::new()
+    };
+
+    println!(
+        "{} {}",
+        "Estimated memory:".blue(),
+        format!(
+            "{} for the input image, {layers}{} for the output image, {} at peak",
+            bytes(input),
+            bytes(output),
+            bytes(peak)
+        )
+        .yellow()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{f32::consts::PI, mem::size_of};
+
+    use super::*;
+    use crate::{
+        filter::Filter,
+        images::{self, AnyImage, Depth},
+        layer::Placed,
+        render::{tests::sampling, Fit},
+        transform,
+    };
+
+    /// A rotation and a perspective transformation, both moving the image away from the origin
+    fn matrices() -> [Array2<f32>; 2] {
+        [
+            transform::rotation(PI / 6.0),
+            ndarray::array![[1.1, 0.2, -7.0], [-0.1, 0.9, 4.0], [0.004, -0.002, 1.0]],
+        ]
+    }
+
+    /// Translates the result of the `matrix` like `--offset`
+    fn offset(matrix: &Array2<f32>, (x, y): (f32, f32)) -> Array2<f32> {
+        transform::translation(x, y).dot(matrix)
+    }
+
+    /// The memory taken by the samples of the `image`
+    fn allocated(image: &AnyImage) -> u128 {
+        (match image {
+            AnyImage::U8(array) => array.len() * size_of::<u8>(),
+            AnyImage::U16(array) => array.len() * size_of::<u16>(),
+            AnyImage::F32(array) => array.len() * size_of::<f32>(),
+        }) as u128
+    }
+
+    #[test]
+    fn formats_bytes_with_binary_units() {
+        assert_eq!(bytes(0), "0 B");
+        assert_eq!(bytes(1023), "1023 B");
+        assert_eq!(bytes(1024), "1.00 KiB");
+        assert_eq!(bytes(3 << 19), "1.50 MiB");
+        assert_eq!(bytes(5 << 30), "5.00 GiB");
+        assert_eq!(bytes(1 << 50), "1024.00 TiB");
+    }
+
+    #[test]
+    fn suggests_the_options_that_fit_the_image() {
+        let dims = (37, 23);
+
+        for matrix in matrices() {
+            // Suggested after an offset that doesn't fit the image yet
+            let given = (3.0, -5.0);
+            let shifted = offset(&matrix, given);
+            let bounds = Bounds::project(&shifted, dims).unwrap();
+            let ((x, y), (out_width, out_height)) = suggest(&bounds, given);
+
+            // Rendering with the suggested options cuts nothing off and leaves no margins
+            let fitted = Bounds::project(&offset(&matrix, (x, y)), dims).unwrap();
+            assert_eq!((fitted.min_x, fitted.min_y), (0, 0));
+            assert_eq!(
+                (fitted.max_x + 1, fitted.max_y + 1),
+                (out_width as isize, out_height as isize)
+            );
+            assert!(!fitted.exceeds((out_width, out_height)));
+
+            // Like fitting the output image with --fit crop
+            let mut cropped = shifted.clone();
+            let mut out_dims = (1, 1);
+            let ((crop_x, crop_y), _) = Fit::Crop.apply(&mut cropped, dims, &mut out_dims).unwrap();
+            assert_eq!(out_dims, (out_width, out_height));
+            assert_eq!((given.0 + crop_x, given.1 + crop_y), (x, y));
+        }
+    }
+
+    #[test]
+    fn estimates_the_memory_rendering_allocates() {
+        let dims = (37, 23);
+        let input = render::tests::pattern::<u8>(dims, 3);
+        let layout = AnyImage::U8(input.clone()).layout();
+        let out_layout = Layout {
+            depth: Depth::Sixteen,
+            channels: 4,
+        };
+
+        // A 16-bit gray and alpha image below the input image, drawn from an 8-bit copy. A dry run
+        // only reads its header.
+        let path = std::env::temp_dir().join(format!("plan-{}.png", std::process::id()));
+        let image = AnyImage::U16(render::tests::pattern::<u16>((19, 29), 2));
+        let images = allocated(&image);
+        let drawn = allocated(&AnyImage::U8(image.converted(2)));
+        images::save_image(image, &path, None).unwrap();
+
+        let scene = |dry_run| Scene {
+            color: [0; 4],
+            below: vec![Placed::load(&path, dry_run).unwrap()],
+            above: Vec::new(),
+        };
+        let (planned, scene) = (scene(true), scene(false));
+        std::fs::remove_file(&path).unwrap();
+
+        for matrix in matrices() {
+            let bounds = Bounds::project(&matrix, dims).unwrap();
+            let ((x, y), out_dims) = suggest(&bounds, (0.0, 0.0));
+            let matrix = offset(&matrix, (x, y));
+            let mut inverse = matrix.clone();
+            inverse.invert().unwrap();
+
+            let (output, _) = crate::render_array(
+                &input,
+                out_dims,
+                out_layout.channels,
+                &scene,
+                &matrix,
+                Some(&inverse),
+                &sampling(Filter::Bilinear),
+            );
+            let saved = allocated(&AnyImage::U16(output.converted::<u16>(out_layout.channels)));
+
+            let memory = memory(dims, out_dims, (layout, out_layout), &planned);
+            assert_eq!(memory.input, allocated(&AnyImage::U8(input.clone())));
+            assert_eq!(memory.images, images);
+            assert_eq!(memory.output, allocated(&output));
+            assert_eq!(
+                memory.peak,
+                memory.input + images + memory.output + saved.max(drawn)
+            );
+            assert_eq!(
+                bytes(memory.input),
+                format!("{:.2} KiB", (37 * 23 * 3) as f64 / 1024.0)
+            );
+        }
+    }
+}
@@ -0,0 +1,756 @@
+use clap::ValueEnum;
+use indicatif::{ProgressBar, ProgressStyle};
+use ndarray::{parallel::prelude::*, Array2, Axis};
+
+use crate::{
+    filter::{self, Antialias, Border, Compositing, Edge, Filter, Footprint},
+    images::{self, Image, ImageArray, Layout, Sample},
+    transform,
+};
+
+/// How the output image is produced from the input image
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum RenderMode {
+    /// Walk the output pixels and sample the input through the inverted matrix (no holes)
+    #[default]
+    Gather,
+
+    /// Walk the input pixels and push them through the matrix (may leave holes)
+    Scatter,
+}
+
+/// The bounding box of the transformed image, in output coordinates (Y pointing up)
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Bounds {
+    pub(crate) min_x: isize,
+    pub(crate) max_x: isize,
+    pub(crate) min_y: isize,
+    pub(crate) max_y: isize,
+}
+
+impl Bounds {
+    /// Projects the corners of a `width`x`height` image through the matrix.
+    ///
+    /// As long as the whole image stays in front of the horizon line, the transformed image is
+    /// a convex quadrilateral, so the extremes are always reached at the corners. Returns
+    /// [`None`] if any corner crosses the horizon, which makes the transformed image unbounded.
+    pub(crate) fn project(matrix: &Array2<f32>, (width, height): (usize, usize)) -> Option<Self> {
+        let mut bounds = Bounds {
+            min_x: isize::MAX,
+            max_x: isize::MIN,
+            min_y: isize::MAX,
+            max_y: isize::MIN,
+        };
+
+        for (new_x, new_y) in project_corners(matrix, (width, height))? {
+            let (new_x, new_y) = (new_x.round() as isize, new_y.round() as isize);
+
+            bounds.min_x = bounds.min_x.min(new_x);
+            bounds.max_x = bounds.max_x.max(new_x);
+            bounds.min_y = bounds.min_y.min(new_y);
+            bounds.max_y = bounds.max_y.max(new_y);
+        }
+
+        Some(bounds)
+    }
+
+    /// Whether any part of the bounding box lies outside of a `width`x`height` image
+    pub(crate) fn exceeds(&self, (width, height): (usize, usize)) -> bool {
+        self.min_x < 0
+            || self.min_y < 0
+            || self.max_x >= width as isize
+            || self.max_y >= height as isize
+    }
+}
+
+/// Projects the centers of the corner pixels of a `width`x`height` image through the matrix.
+///
+/// Returns [`None`] if any corner crosses the horizon.
+fn project_corners(
+    matrix: &Array2<f32>,
+    (width, height): (usize, usize),
+) -> Option<[(f32, f32); 4]> {
+    let (right, top) = ((width - 1) as f32, (height - 1) as f32);
+
+    Some([
+        transform_point(matrix, 0.0, 0.0)?,
+        transform_point(matrix, right, 0.0)?,
+        transform_point(matrix, 0.0, top)?,
+        transform_point(matrix, right, top)?,
+    ])
+}
+
+/// How the output image is fitted to the transformed image
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Fit {
+    /// Grow the output image so that nothing is cut off
+    Expand,
+
+    /// Make the output image exactly as big as the transformed image
+    Crop,
+
+    /// Scale the transformed image to fit inside the output image, keeping its aspect ratio, and
+    /// center it
+    #[value(name = "fit-inside")]
+    Inside,
+}
+
+impl Fit {
+    /// Adjusts the matrix and the output dimensions of a transformation of a `width`x`height`
+    /// image, returning the extra translation and scale that were applied.
+    ///
+    /// Returns [`None`] if the transformed image crosses the horizon, so it can't be fitted.
+    pub(crate) fn apply(
+        self,
+        matrix: &mut Array2<f32>,
+        (width, height): (usize, usize),
+        out_dims: &mut (usize, usize),
+    ) -> Option<((f32, f32), f32)> {
+        let (offset, scale) = match self {
+            Fit::Expand | Fit::Crop => {
+                let mut bounds = Bounds::project(matrix, (width, height))?;
+
+                if self == Fit::Expand {
+                    bounds.min_x = bounds.min_x.min(0);
+                    bounds.min_y = bounds.min_y.min(0);
+                    bounds.max_x = bounds.max_x.max(out_dims.0 as isize - 1);
+                    bounds.max_y = bounds.max_y.max(out_dims.1 as isize - 1);
+                }
+
+                *out_dims = (
+                    (bounds.max_x - bounds.min_x + 1) as usize,
+                    (bounds.max_y - bounds.min_y + 1) as usize,
+                );
+
+                ((-bounds.min_x as f32, -bounds.min_y as f32), 1.0)
+            }
+            Fit::Inside => {
+                let corners = project_corners(matrix, (width, height))?;
+
+                let (mut min_x, mut max_x) = (f32::INFINITY, f32::NEG_INFINITY);
+                let (mut min_y, mut max_y) = (f32::INFINITY, f32::NEG_INFINITY);
+                for (x, y) in corners {
+                    (min_x, max_x) = (min_x.min(x), max_x.max(x));
+                    (min_y, max_y) = (min_y.min(y), max_y.max(y));
+                }
+
+                // Fit the centers of the outermost pixels onto the centers of the edge pixels
+                let (out_right, out_top) = ((out_dims.0 - 1) as f32, (out_dims.1 - 1) as f32);
+                let scale = [(out_right, max_x - min_x), (out_top, max_y - min_y)]
+                    .into_iter()
+                    .filter(|&(_, size)| size > 0.0)
+                    .map(|(out_size, size)| out_size / size)
+                    .fold(f32::INFINITY, f32::min);
+                let scale = if scale.is_finite() { scale } else { 1.0 };
+
+                *matrix = transform::scale(scale, scale).dot(matrix);
+
+                (
+                    (
+                        out_right / 2.0 - scale * (min_x + max_x) / 2.0,
+                        out_top / 2.0 - scale * (min_y + max_y) / 2.0,
+                    ),
+                    scale,
+                )
+            }
+        };
+
+        *matrix = transform::translation(offset.0, offset.1).dot(matrix);
+
+        Some((offset, scale))
+    }
+}
+
+/// Whether the homogeneous 3x3 `matrix` is a perspective transformation rather than an affine one
+pub(crate) fn is_projective(matrix: &Array2<f32>) -> bool {
+    matrix[[2, 0]] != 0.0 || matrix[[2, 1]] != 0.0 || matrix[[2, 2]] != 1.0
+}
+
+/// Applies the homogeneous 3x3 `matrix` to the point (`x`, `y`), including the perspective divide.
+///
+/// Returns [`None`] if the point ends up on or behind the horizon line (W <= 0).
+pub(crate) fn transform_point(matrix: &Array2<f32>, x: f32, y: f32) -> Option<(f32, f32)> {
+    // The X terms are added last, like in `affine_row`
+    let w = matrix[[2, 0]] * x + (matrix[[2, 1]] * y + matrix[[2, 2]]);
+    if w <= 0.0 {
+        return None;
+    }
+
+    Some((
+        (matrix[[0, 0]] * x + (matrix[[0, 1]] * y + matrix[[0, 2]])) / w,
+        (matrix[[1, 0]] * x + (matrix[[1, 1]] * y + matrix[[1, 2]])) / w,
+    ))
+}
+
+/// Computes the source positions of the output row at `out_y` through the affine `inverse`,
+/// in array coordinates (Y pointing down) of an input image with the given `height`.
+///
+/// Moving one pixel to the right adds the first column of the matrix to the source position, so
+/// the row is the position of its first pixel plus a multiple of that step. Multiplying instead
+/// of accumulating the steps doesn't drift, gives exactly the same results as
+/// [`transform_point`], and lets the compiler vectorize the loops. Without antialiasing,
+/// [`filter::sample_row`] then samples the nearest and bilinear filters over the whole row.
+fn affine_row(inverse: &Array2<f32>, out_y: f32, height: usize, xs: &mut [f32], ys: &mut [f32]) {
+    let (step_x, step_y) = (inverse[[0, 0]], inverse[[1, 0]]);
+    let start_x = inverse[[0, 1]] * out_y + inverse[[0, 2]];
+    let start_y = inverse[[1, 1]] * out_y + inverse[[1, 2]];
+    let top = (height - 1) as f32;
+
+    for (x, src_x) in xs.iter_mut().enumerate() {
+        *src_x = step_x * x as f32 + start_x;
+    }
+
+    for (x, src_y) in ys.iter_mut().enumerate() {
+        *src_y = top - (step_y * x as f32 + start_y);
+    }
+}
+
+/// The derivatives of the homogeneous 3x3 `matrix` around the point (`x`, `y`), converted to
+/// array coordinates (Y pointing down): the columns are the steps in the transformed image when
+/// moving by one pixel along the X and Y axes.
+pub(crate) fn jacobian(matrix: &Array2<f32>, x: f32, y: f32) -> [[f32; 2]; 2] {
+    let u = matrix[[0, 0]] * x + matrix[[0, 1]] * y + matrix[[0, 2]];
+    let v = matrix[[1, 0]] * x + matrix[[1, 1]] * y + matrix[[1, 2]];
+    let w = matrix[[2, 0]] * x + matrix[[2, 1]] * y + matrix[[2, 2]];
+
+    let dx_dx = (matrix[[0, 0]] * w - u * matrix[[2, 0]]) / (w * w);
+    let dx_dy = (matrix[[0, 1]] * w - u * matrix[[2, 1]]) / (w * w);
+    let dy_dx = (matrix[[1, 0]] * w - v * matrix[[2, 0]]) / (w * w);
+    let dy_dy = (matrix[[1, 1]] * w - v * matrix[[2, 1]]) / (w * w);
+
+    [[dx_dx, -dx_dy], [-dy_dx, dy_dy]]
+}
+
+pub(crate) fn progress_bar(len: usize) -> ProgressBar {
+    let pb = ProgressBar::new(len as u64);
+    pb.set_style(ProgressStyle::with_template("{wide_bar} {percent_precise}% ({eta})").unwrap());
+    pb
+}
+
+/// Pushes every input pixel through `matrix` and composites it over the pixel of `output` it
+/// lands on according to `compositing`.
+///
+/// Matrices that enlarge the image leave holes, and matrices that shrink it make pixels pile up
+/// on each other.
+pub(crate) fn scatter<T: Sample>(
+    input: &ImageArray<T>,
+    output: &mut ImageArray<T>,
+    matrix: &Array2<f32>,
+    compositing: &Compositing,
+) {
+    let (height, width, _) = input.dim();
+    let (out_height, out_width, channels) = output.dim();
+
+    let pb = progress_bar(height * width);
+
+    for y in 0..height {
+        for x in 0..width {
+            let Some((new_x, new_y)) = transform_point(matrix, x as f32, (height - y - 1) as f32)
+            else {
+                pb.inc(1);
+                continue;
+            };
+
+            let new_x = new_x.round() as isize;
+            let new_y = out_height as isize - new_y.round() as isize - 1;
+
+            if new_x >= 0 && new_x < out_width as isize && new_y >= 0 && new_y < out_height as isize
+            {
+                let (new_x, new_y) = (new_x as usize, new_y as usize);
+                let below = images::to_rgba(channels, |channel| output[[new_y, new_x, channel]]);
+                let pixel = compositing.composite(input.pixel(x, y), below, false);
+
+                let pixel = images::from_rgba(pixel, channels);
+                for (channel, value) in pixel.into_iter().take(channels).enumerate() {
+                    output[[new_y, new_x, channel]] = value;
+                }
+            }
+
+            pb.inc(1);
+        }
+    }
+
+    pb.finish();
+}
+
+/// How the input image is sampled and composited over the output image in gather mode
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Sampling<T = u8> {
+    pub(crate) filter: Filter,
+    pub(crate) border: Border<T>,
+    pub(crate) antialias: Antialias,
+
+    /// The amount of samples along each axis of an output pixel when supersampling
+    pub(crate) samples: usize,
+
+    /// Whether the colors are blended in linear light instead of sRGB
+    pub(crate) linear: bool,
+
+    pub(crate) compositing: Compositing,
+}
+
+impl Sampling {
+    /// Converts the border color to another type of samples
+    pub(crate) fn convert<T: Sample>(&self) -> Sampling<T> {
+        Sampling {
+            filter: self.filter,
+            border: self.border.convert(),
+            antialias: self.antialias,
+            samples: self.samples,
+            linear: self.linear,
+            compositing: self.compositing,
+        }
+    }
+}
+
+/// How far from the sampled positions (in input pixels) rendering the output pixels within
+/// `corners` (in output coordinates) reads along the X and Y axes
+pub(crate) fn reach<T>(
+    inverse: &Array2<f32>,
+    corners: &[(f32, f32); 4],
+    sampling: &Sampling<T>,
+) -> (f64, f64) {
+    let radius = sampling.filter.radius() as f64;
+
+    match sampling.antialias {
+        Antialias::Footprint if is_projective(inverse) => {
+            // The jacobian of a perspective transformation is (A - p * c^T) / w, with the linear
+            // part A, the perspective row c and the transformed point p. Both p and w are the
+            // most extreme at the corners, which bounds the largest singular value.
+            let m = inverse.mapv(|v| v as f64);
+            let linear = [m[[0, 0]], m[[0, 1]], m[[1, 0]], m[[1, 1]]]
+                .iter()
+                .map(|v| v * v)
+                .sum::<f64>()
+                .sqrt();
+            let perspective = m[[2, 0]].hypot(m[[2, 1]]);
+
+            let (mut point, mut w) = (0.0_f64, f64::INFINITY);
+            for &(x, y) in corners {
+                let (x, y) = (x as f64, y as f64);
+                let corner_w = m[[2, 0]] * x + m[[2, 1]] * y + m[[2, 2]];
+                let transformed_x = (m[[0, 0]] * x + m[[0, 1]] * y + m[[0, 2]]) / corner_w;
+                let transformed_y = (m[[1, 0]] * x + m[[1, 1]] * y + m[[1, 2]]) / corner_w;
+
+                point = point.max(transformed_x.hypot(transformed_y));
+                w = w.min(corner_w);
+            }
+
+            let reach = radius * ((linear + point * perspective) / w).max(1.0);
+            (reach, reach)
+        }
+        Antialias::Footprint => {
+            let (x, y) = Footprint::new(jacobian(inverse, 0.0, 0.0)).reach(sampling.filter);
+            (x as f64, y as f64)
+        }
+        Antialias::None | Antialias::Supersample => (radius, radius),
+    }
+}
+
+/// Which positions rendering the output image in gather mode reads
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Coverage {
+    /// Only pixels inside the input image
+    Inside,
+
+    /// Also positions beyond the edges of the input image
+    Edges,
+
+    /// Some output pixels lie beyond the horizon, where nothing is read and the background is kept
+    Horizon,
+}
+
+impl Coverage {
+    /// Finds which positions rendering an `out_width`x`out_height` output image from a
+    /// `width`x`height` input image through `inverse` reads.
+    ///
+    /// The output image is a rectangle, which the inverse maps onto a convex quadrilateral in the
+    /// input image, so only its corners and the reach of the filter around them are checked.
+    pub(crate) fn find<T>(
+        inverse: &Array2<f32>,
+        (width, height): (usize, usize),
+        (out_width, out_height): (usize, usize),
+        sampling: &Sampling<T>,
+    ) -> Self {
+        // Supersamples are spread over the output pixels, single samples are taken at their centers
+        let margin = match sampling.antialias {
+            Antialias::Supersample => 0.5,
+            Antialias::None | Antialias::Footprint => 0.0,
+        };
+        let (left, right) = (-margin, (out_width - 1) as f32 + margin);
+        let (bottom, top) = (-margin, (out_height - 1) as f32 + margin);
+        let out_corners = [(left, bottom), (right, bottom), (right, top), (left, top)];
+
+        let (reach_x, reach_y) = reach(inverse, &out_corners, sampling);
+
+        // Pixels at the reach of the filter have no weight, and a little tolerance keeps rounding
+        // errors from counting them
+        let (min, max_x, max_y) = (-1.0 - 1e-3, width as f64 + 1e-3, height as f64 + 1e-3);
+
+        let mut coverage = Coverage::Inside;
+        for (x, y) in out_corners {
+            let Some((src_x, src_y)) = transform_point(inverse, x, y) else {
+                return Coverage::Horizon;
+            };
+            let (src_x, src_y) = (src_x as f64, (height - 1) as f64 - src_y as f64);
+
+            if src_x - reach_x < min
+                || src_y - reach_y < min
+                || src_x + reach_x > max_x
+                || src_y + reach_y > max_y
+            {
+                coverage = Coverage::Edges;
+            }
+        }
+
+        coverage
+    }
+
+    /// The layout of an output image rendered from an input image with the `input` layout and
+    /// composited over a background with `canvas` channels, with color added if the colors read
+    /// beyond the edges of the input image (see the border of `sampling`) or the background need
+    /// it. Alpha is only kept if the background shows through and isn't opaque.
+    ///
+    /// Without a coverage, the image is rendered in scatter mode, which may leave holes.
+    pub(crate) fn layout(
+        coverage: Option<Self>,
+        input: Layout,
+        sampling: &Sampling,
+        canvas: usize,
+    ) -> Layout {
+        let border = &sampling.border;
+        let reads_color = matches!(border.edge, Edge::Transparent | Edge::Background);
+
+        let (border_color, uncovered) = match coverage {
+            Some(Coverage::Inside) => (false, false),
+            Some(Coverage::Edges) => (reads_color, reads_color),
+            Some(Coverage::Horizon) => (reads_color, true),
+            None => (false, true),
+        };
+        let shows_through =
+            uncovered || input.has_alpha() || !sampling.compositing.keeps_over_transparent();
+        let canvas = Layout {
+            depth: input.depth,
+            channels: canvas,
+        };
+
+        let color = input.has_color()
+            || (border_color && images::color_channels(border.color) >= 3)
+            || (shows_through && canvas.has_color());
+        let alpha = shows_through && canvas.has_alpha();
+
+        Layout {
+            depth: input.depth,
+            channels: images::channels(color, alpha),
+        }
+    }
+}
+
+/// Fills every output pixel by sampling its position in the input through `inverse`,
+/// which has to be the inverse of the transformation matrix.
+///
+/// `output` holds the rows starting at `first_row` of an output image that is `out_height` rows
+/// tall, so that it can be rendered in bands. Progress is reported to `pb`.
+///
+/// Returns the average amount of input pixels read per output pixel.
+pub(crate) fn gather<T: Sample, I: Image<T> + ?Sized>(
+    input: &I,
+    output: &mut ImageArray<T>,
+    first_row: usize,
+    out_height: usize,
+    inverse: &Array2<f32>,
+    sampling: &Sampling<T>,
+    pb: &ProgressBar,
+) -> f32 {
+    let Sampling {
+        filter,
+        ref border,
+        antialias,
+        samples,
+        linear,
+        ref compositing,
+    } = *sampling;
+
+    let (width, height) = input.dimensions();
+    let (rows, out_width, channels) = output.dim();
+
+    // Affine transformations look the same everywhere, so the footprint only has to be computed once
+    let affine_footprint =
+        (!is_projective(inverse)).then(|| Footprint::new(jacobian(inverse, 0.0, 0.0)));
+
+    // The offsets of the supersampling grid within an output pixel
+    let subsamples: Vec<(f32, f32)> = match antialias {
+        Antialias::Supersample => (0..samples * samples)
+            .map(|i| {
+                (
+                    ((i % samples) as f32 + 0.5) / samples as f32 - 0.5,
+                    ((i / samples) as f32 + 0.5) / samples as f32 - 0.5,
+                )
+            })
+            .collect(),
+        _ => vec![(0.0, 0.0)],
+    };
+
+    // Single samples of affine transformations are positioned a whole row at a time, and without
+    // antialiasing also sampled a whole row at a time
+    let stepped = !is_projective(inverse) && antialias != Antialias::Supersample;
+    let row_sampled = stepped && antialias == Antialias::None;
+
+    // The rows are rendered independently of each other, and only the amount of taps read by
+    // the footprints is collected from them, in order, so the result doesn't depend on the threads
+    let footprint_taps: f64 = output
+        .axis_iter_mut(Axis(0))
+        .into_par_iter()
+        .enumerate()
+        .map_init(
+            || {
+                (
+                    vec![0.0; out_width],
+                    vec![0.0; out_width],
+                    vec![[0.0; 4]; out_width],
+                )
+            },
+            |(src_xs, src_ys, values), (y, mut row)| {
+                let mut footprint_taps = 0.0;
+                let row = row
+                    .as_slice_mut()
+                    .expect("output images are stored row after row");
+
+                let out_y = (out_height - (first_row + y) - 1) as f32;
+                if stepped {
+                    affine_row(inverse, out_y, height, src_xs, src_ys);
+                }
+                if row_sampled {
+                    let positions = (&src_xs[..], &src_ys[..]);
+                    filter::sample_row(input, positions, filter, border, linear, values);
+                }
+
+                // The position of the center of an output pixel in the input, in array coordinates
+                let source = |x: usize| {
+                    if stepped {
+                        Some((src_xs[x], src_ys[x]))
+                    } else {
+                        transform_point(inverse, x as f32, out_y)
+                            .map(|(src_x, src_y)| (src_x, (height - 1) as f32 - src_y))
+                    }
+                };
+
+                for (x, output) in row.chunks_exact_mut(channels).enumerate() {
+                    let out_x = x as f32;
+
+                    let pixel = match antialias {
+                        Antialias::None if row_sampled => Some(values[x]),
+                        Antialias::None => source(x).map(|(src_x, src_y)| {
+                            filter::sample(input, src_x, src_y, filter, border, linear)
+                        }),
+                        Antialias::Footprint => source(x).map(|(src_x, src_y)| {
+                            let footprint = affine_footprint
+                                .unwrap_or_else(|| Footprint::new(jacobian(inverse, out_x, out_y)));
+
+                            if border.covers(src_x, src_y, (width, height)) {
+                                footprint_taps +=
+                                    footprint.taps(filter).min((width * height) as f32) as f64;
+                            }
+
+                            filter::sample_footprint(
+                                input, src_x, src_y, filter, border, &footprint, linear,
+                            )
+                        }),
+                        Antialias::Supersample => {
+                            let mut sum = [0.0f32; 4];
+                            let mut hit = false;
+
+                            for (dx, dy) in &subsamples {
+                                let sample = transform_point(inverse, out_x + dx, out_y - dy).map(
+                                    |(src_x, src_y)| {
+                                        let src_y = (height - 1) as f32 - src_y;
+                                        filter::sample(input, src_x, src_y, filter, border, linear)
+                                    },
+                                );
+
+                                // Samples beyond the horizon keep the background
+                                let sample = match sample {
+                                    Some(sample) => {
+                                        hit = true;
+                                        sample
+                                    }
+                                    None => filter::blendable(
+                                        images::to_rgba(channels, |channel| output[channel]),
+                                        linear,
+                                    ),
+                                };
+
+                                for (value, sample) in sum.iter_mut().zip(sample) {
+                                    *value += sample;
+                                }
+                            }
+
+                            hit.then(|| sum.map(|value| value / subsamples.len() as f32))
+                        }
+                    };
+
+                    if let Some(values) = pixel {
+                        // The output image starts out filled with the background, which the
+                        // samples are composited over
+                        let below = images::to_rgba(channels, |channel| output[channel]);
+                        let values =
+                            if below[3] == T::default() && compositing.keeps_over_transparent() {
+                                values
+                            } else {
+                                compositing.over::<T>(values, filter::blendable(below, linear))
+                            };
+
+                        let pixel = images::from_rgba(filter::blended(values, linear), channels);
+                        output.copy_from_slice(&pixel[..channels]);
+                    }
+                }
+
+                pb.inc(out_width as u64);
+                footprint_taps
+            },
+        )
+        .collect::<Vec<f64>>()
+        .into_iter()
+        .sum();
+
+    match antialias {
+        Antialias::None => filter.taps() as f32,
+        Antialias::Supersample => (subsamples.len() * filter.taps()) as f32,
+        Antialias::Footprint => (footprint_taps / (out_width * rows) as f64) as f32,
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::time::Instant;
+
+    use super::*;
+    use crate::matrix_ext::MatrixExt;
+
+    /// A `width`x`height` image with `channels` channels and a different color in every pixel
+    pub(crate) fn pattern<T: Sample>(
+        (width, height): (usize, usize),
+        channels: usize,
+    ) -> ImageArray<T> {
+        ImageArray::from_shape_fn((height, width, channels), |(y, x, channel)| {
+            let value = (x * 7919 + y * 3571 + channel * 1013) % 4099;
+            T::from_f32(value as f32 / 4098.0 * T::MAX)
+        })
+    }
+
+    /// Sampling with `filter` and nothing else, reading transparent pixels outside of the image
+    pub(crate) fn sampling<T: Sample>(filter: Filter) -> Sampling<T> {
+        Sampling {
+            filter,
+            border: Border {
+                edge: Edge::Transparent,
+                color: [T::default(); 4],
+            },
+            antialias: Antialias::None,
+            samples: 4,
+            linear: false,
+            compositing: Compositing::default(),
+        }
+    }
+
+    /// Renders `input` through `inverse` in gather mode into a transparent `width`x`height`
+    /// image with 4 channels
+    pub(crate) fn gathered<T: Sample>(
+        input: &ImageArray<T>,
+        (width, height): (usize, usize),
+        inverse: &Array2<f32>,
+        sampling: &Sampling<T>,
+    ) -> ImageArray<T> {
+        let mut output = images::filled((width, height), 4, [0; 4]);
+        gather(
+            input,
+            &mut output,
+            0,
+            height,
+            inverse,
+            sampling,
+            &ProgressBar::hidden(),
+        );
+
+        output
+    }
+
+    /// Renders `input` through `matrix` into an image of the same size, returning it along with
+    /// the time rendering took.
+    ///
+    /// With `stepped`, the inverse matrix is used as it is, otherwise it's scaled by 2. That's the
+    /// same transformation, but it looks like a perspective one and is positioned and sampled
+    /// pixel by pixel.
+    fn rendered<T: Sample>(
+        input: &ImageArray<T>,
+        matrix: &Array2<f32>,
+        sampling: &Sampling<T>,
+        stepped: bool,
+    ) -> (ImageArray<T>, f64) {
+        let mut inverse = matrix.clone();
+        inverse.invert().unwrap();
+        if !stepped {
+            inverse *= 2.0;
+        }
+
+        let start = Instant::now();
+        let output = gathered(input, input.dimensions(), &inverse, sampling);
+
+        (output, start.elapsed().as_secs_f64())
+    }
+
+    /// Checks that rendering `input` stepped gives the same pixels as transforming every point,
+    /// close to and beyond the edges of the image and between pixels
+    fn check_stepping<T: Sample>(input: &ImageArray<T>) {
+        let matrices = [
+            transform::around(&transform::rotation(0.3), (30.0, 20.0)),
+            transform::translation(-10.5, 3.25).dot(&transform::scale(1.5, 0.5)),
+            transform::translation(2.5, -0.5),
+        ];
+
+        for matrix in &matrices {
+            for filter in [Filter::Nearest, Filter::Bilinear, Filter::Bicubic] {
+                for edge in [Edge::Transparent, Edge::Clamp, Edge::Repeat, Edge::Mirror] {
+                    let mut sampling = sampling(filter);
+                    sampling.border.edge = edge;
+
+                    let (stepped, _) = rendered(input, matrix, &sampling, true);
+                    let (transformed, _) = rendered(input, matrix, &sampling, false);
+                    assert_eq!(stepped, transformed, "{matrix}, {filter:?}, {edge:?}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn steps_like_transforming_every_point() {
+        check_stepping::<u8>(&pattern((61, 41), 3));
+        check_stepping::<u8>(&pattern((61, 41), 2));
+        check_stepping::<u16>(&pattern((61, 41), 4));
+        check_stepping::<f32>(&pattern((61, 41), 1));
+    }
+
+    /// Compares stepping with transforming every point on a 7680x4320 image. Run it with
+    /// `cargo test --release -- --ignored --nocapture`.
+    #[test]
+    #[ignore = "takes a while, run it in release mode"]
+    fn benchmark_stepping_on_8k_images() {
+        let input = pattern::<u8>((7680, 4320), 3);
+        let rotation = transform::around(&transform::rotation(0.5), (3840.0, 2160.0));
+        let scale = transform::scale(0.75, 0.75);
+
+        for (name, matrix) in [("rotate", &rotation), ("scale 0.75", &scale)] {
+            for filter in [Filter::Nearest, Filter::Bilinear, Filter::Bicubic] {
+                let sampling = sampling(filter);
+                let (stepped, after) = rendered(&input, matrix, &sampling, true);
+                let (transformed, before) = rendered(&input, matrix, &sampling, false);
+                assert_eq!(stepped, transformed);
+
+                println!(
+                    "{name}, {filter:?}: {before:.2} s transforming every point, {after:.2} s stepped ({:.2}x)",
+                    before / after
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,514 @@
+use std::path::Path;
+
+use clap::ValueEnum;
+use color_eyre::{eyre::bail, Result};
+use ndarray::{Array1, Array2};
+
+use crate::matrix_ext::Lu;
+
+/// Solves the square linear system `a * x = b` using an LU decomposition with partial pivoting.
+///
+/// Returns [`None`] if the system is singular.
+pub(crate) fn solve_linear(a: Array2<f64>, b: Array1<f64>) -> Option<Array1<f64>> {
+    Lu::new(&a).ok()?.solve(&b).ok()
+}
+
+/// The kind of transformation fitted to point correspondences
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum Model {
+    /// Rotation, uniform scale and translation (needs at least 2 points)
+    Similarity,
+
+    /// Any linear transformation and translation (needs at least 3 points)
+    #[default]
+    Affine,
+
+    /// A perspective transformation (needs at least 4 points)
+    Projective,
+}
+
+impl Model {
+    /// The minimal amount of point pairs which determine the model
+    pub(crate) fn min_points(self) -> usize {
+        match self {
+            Model::Similarity => 2,
+            Model::Affine => 3,
+            Model::Projective => 4,
+        }
+    }
+
+    /// Builds the linear equations `a * h = b` for the parameters `h` of the model, two for every
+    /// pair of points.
+    fn equations(self, from: &[[f64; 2]], to: &[[f64; 2]]) -> (Array2<f64>, Array1<f64>) {
+        let unknowns = match self {
+            Model::Similarity => 4,
+            Model::Affine => 6,
+            Model::Projective => 8,
+        };
+
+        let mut a = Array2::zeros((2 * from.len(), unknowns));
+        let mut b = Array1::zeros(2 * from.len());
+
+        for (i, (&[x, y], &[new_x, new_y])) in from.iter().zip(to).enumerate() {
+            let (row_x, row_y) = match self {
+                // x' = h0 x - h1 y + h2
+                // y' = h1 x + h0 y + h3
+                Model::Similarity => (vec![x, -y, 1.0, 0.0], vec![y, x, 0.0, 1.0]),
+                // x' = h0 x + h1 y + h2
+                // y' = h3 x + h4 y + h5
+                Model::Affine => (
+                    vec![x, y, 1.0, 0.0, 0.0, 0.0],
+                    vec![0.0, 0.0, 0.0, x, y, 1.0],
+                ),
+                // x' = (h0 x + h1 y + h2) / (h6 x + h7 y + 1)
+                // y' = (h3 x + h4 y + h5) / (h6 x + h7 y + 1)
+                Model::Projective => (
+                    vec![x, y, 1.0, 0.0, 0.0, 0.0, -x * new_x, -y * new_x],
+                    vec![0.0, 0.0, 0.0, x, y, 1.0, -x * new_y, -y * new_y],
+                ),
+            };
+
+            a.row_mut(2 * i).assign(&Array1::from(row_x));
+            b[2 * i] = new_x;
+
+            a.row_mut(2 * i + 1).assign(&Array1::from(row_y));
+            b[2 * i + 1] = new_y;
+        }
+
+        (a, b)
+    }
+
+    /// Turns the solved parameters into a homogeneous 3x3 matrix
+    fn matrix(self, h: &Array1<f64>) -> Array2<f64> {
+        let values = match self {
+            Model::Similarity => vec![h[0], -h[1], h[2], h[1], h[0], h[3], 0.0, 0.0, 1.0],
+            Model::Affine => vec![h[0], h[1], h[2], h[3], h[4], h[5], 0.0, 0.0, 1.0],
+            Model::Projective => vec![h[0], h[1], h[2], h[3], h[4], h[5], h[6], h[7], 1.0],
+        };
+
+        Array2::from_shape_vec((3, 3), values).unwrap()
+    }
+}
+
+/// Computes the homography mapping each of the four `from` points onto the corresponding `to`
+/// point, as a homogeneous 3x3 matrix.
+pub(crate) fn homography(from: &[[f64; 2]; 4], to: &[[f64; 2]; 4]) -> Result<Array2<f32>> {
+    for (name, points) in [("--from", from), ("--to", to)] {
+        if has_collinear_triple(points) {
+            bail!("Three of the {name} corners lie on a line, no perspective transformation maps them onto each other");
+        }
+    }
+
+    let (a, b) = Model::Projective.equations(from, to);
+
+    let Some(h) = solve_linear(a, b) else {
+        bail!(
+            "The corners are degenerate, no perspective transformation maps them onto each other"
+        );
+    };
+
+    Ok(Model::Projective.matrix(&h).mapv(|v| v as f32))
+}
+
+/// The result of fitting a model to point correspondences
+pub(crate) struct Fit {
+    /// The fitted homogeneous 3x3 matrix
+    pub(crate) matrix: Array2<f32>,
+
+    /// The distance between every transformed `from` point and its `to` point
+    pub(crate) residuals: Vec<f64>,
+
+    /// Whether every point pair was used for the fit (always true without RANSAC)
+    pub(crate) inliers: Vec<bool>,
+}
+
+impl Fit {
+    /// The root mean square of the residuals of the inliers
+    pub(crate) fn rms(&self) -> f64 {
+        let (sum, count) = self
+            .residuals
+            .iter()
+            .zip(&self.inliers)
+            .filter(|(_, &inlier)| inlier)
+            .fold((0.0, 0), |(sum, count), (residual, _)| {
+                (sum + residual * residual, count + 1)
+            });
+
+        (sum / count as f64).sqrt()
+    }
+}
+
+/// Fits `model` to the point correspondences in the least-squares sense.
+///
+/// With a RANSAC `threshold` (in pixels), random minimal subsets of the points are tried first,
+/// and only the points agreeing with the best one within the threshold are used for the fit.
+pub(crate) fn fit(
+    model: Model,
+    from: &[[f64; 2]],
+    to: &[[f64; 2]],
+    threshold: Option<f64>,
+) -> Result<Fit> {
+    if from.len() < model.min_points() {
+        bail!(
+            "Fitting the {:?} model needs at least {} point pairs, got {}",
+            model,
+            model.min_points(),
+            from.len()
+        );
+    }
+
+    let inliers = match threshold {
+        Some(threshold) => ransac(model, from, to, threshold)?,
+        None => vec![true; from.len()],
+    };
+
+    let (from_inliers, to_inliers): (Vec<_>, Vec<_>) = from
+        .iter()
+        .zip(to)
+        .zip(&inliers)
+        .filter(|(_, &inlier)| inlier)
+        .map(|((from, to), _)| (*from, *to))
+        .unzip();
+
+    let Some(matrix) = least_squares(model, &from_inliers, &to_inliers) else {
+        bail!("The points are degenerate, the {model:?} model can't be fitted to them");
+    };
+
+    let residuals = from
+        .iter()
+        .zip(to)
+        .map(|(from, to)| residual(&matrix, from, to))
+        .collect();
+
+    Ok(Fit {
+        matrix: matrix.mapv(|v| v as f32),
+        residuals,
+        inliers,
+    })
+}
+
+/// Solves the (normalized) normal equations of the model for the given points
+fn least_squares(model: Model, from: &[[f64; 2]], to: &[[f64; 2]]) -> Option<Array2<f64>> {
+    // Moving the points around the origin with an average distance of sqrt(2) keeps the equations
+    // well-conditioned, even for coordinates in the thousands
+    let (from_norm, from_points) = normalize(from);
+    let (to_norm, to_points) = normalize(to);
+
+    let (a, b) = model.equations(&from_points, &to_points);
+    let h = solve_linear(a.t().dot(&a), a.t().dot(&b))?;
+
+    // Undo the normalization: M = T_to^-1 * M' * T_from
+    let [scale, x, y] = to_norm;
+    let to_denorm = Array2::from_shape_vec(
+        (3, 3),
+        vec![1.0 / scale, 0.0, x, 0.0, 1.0 / scale, y, 0.0, 0.0, 1.0],
+    )
+    .unwrap();
+
+    let [scale, x, y] = from_norm;
+    let from_norm = Array2::from_shape_vec(
+        (3, 3),
+        vec![
+            scale,
+            0.0,
+            -x * scale,
+            0.0,
+            scale,
+            -y * scale,
+            0.0,
+            0.0,
+            1.0,
+        ],
+    )
+    .unwrap();
+
+    let matrix = to_denorm.dot(&model.matrix(&h)).dot(&from_norm);
+
+    Some(&matrix / matrix[[2, 2]])
+}
+
+/// Returns the scale and centroid of the normalization, and the normalized points
+fn normalize(points: &[[f64; 2]]) -> ([f64; 3], Vec<[f64; 2]>) {
+    let count = points.len() as f64;
+    let x = points.iter().map(|p| p[0]).sum::<f64>() / count;
+    let y = points.iter().map(|p| p[1]).sum::<f64>() / count;
+
+    let distance = points
+        .iter()
+        .map(|p| ((p[0] - x).powi(2) + (p[1] - y).powi(2)).sqrt())
+        .sum::<f64>()
+        / count;
+    let scale = if distance > 0.0 {
+        std::f64::consts::SQRT_2 / distance
+    } else {
+        1.0
+    };
+
+    let points = points
+        .iter()
+        .map(|p| [(p[0] - x) * scale, (p[1] - y) * scale])
+        .collect();
+
+    ([scale, x, y], points)
+}
+
+/// The distance between `from` transformed by `matrix` and `to`
+fn residual(matrix: &Array2<f64>, from: &[f64; 2], to: &[f64; 2]) -> f64 {
+    let w = matrix[[2, 0]] * from[0] + matrix[[2, 1]] * from[1] + matrix[[2, 2]];
+    let x = (matrix[[0, 0]] * from[0] + matrix[[0, 1]] * from[1] + matrix[[0, 2]]) / w;
+    let y = (matrix[[1, 0]] * from[0] + matrix[[1, 1]] * from[1] + matrix[[1, 2]]) / w;
+
+    ((x - to[0]).powi(2) + (y - to[1]).powi(2)).sqrt()
+}
+
+/// Finds the largest set of points agreeing with a model fitted to a random minimal subset
+fn ransac(model: Model, from: &[[f64; 2]], to: &[[f64; 2]], threshold: f64) -> Result<Vec<bool>> {
+    const ITERATIONS: usize = 1000;
+
+    // A fixed seed makes the results reproducible
+    let mut rng = XorShift(0x2545_f491_4f6c_dd1d);
+    let mut best: Option<Vec<bool>> = None;
+
+    for _ in 0..ITERATIONS {
+        let mut indices: Vec<usize> = Vec::with_capacity(model.min_points());
+        while indices.len() < model.min_points() {
+            let index = rng.next() as usize % from.len();
+            if !indices.contains(&index) {
+                indices.push(index);
+            }
+        }
+
+        let sample_from: Vec<_> = indices.iter().map(|&i| from[i]).collect();
+        let sample_to: Vec<_> = indices.iter().map(|&i| to[i]).collect();
+
+        let Some(matrix) = least_squares(model, &sample_from, &sample_to) else {
+            continue;
+        };
+
+        let inliers: Vec<bool> = from
+            .iter()
+            .zip(to)
+            .map(|(from, to)| residual(&matrix, from, to) <= threshold)
+            .collect();
+
+        let count = |inliers: &[bool]| inliers.iter().filter(|&&inlier| inlier).count();
+        if best
+            .as_ref()
+            .is_none_or(|best| count(&inliers) > count(best))
+        {
+            best = Some(inliers);
+        }
+    }
+
+    match best {
+        Some(best) if best.iter().filter(|&&inlier| inlier).count() >= model.min_points() => {
+            Ok(best)
+        }
+        _ => bail!(
+            "RANSAC couldn't find {} point pairs agreeing within {threshold} pixels",
+            model.min_points()
+        ),
+    }
+}
+
+/// A tiny xorshift pseudo-random number generator, good enough for picking RANSAC samples
+struct XorShift(u64);
+
+impl XorShift {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+/// Reads point pairs from a CSV file with the columns `X,Y,X',Y'`, where (X, Y) is a point in
+/// the input image and (X', Y') is where it should end up in the output image.
+///
+/// Empty lines, lines starting with `#` and a header line before the points are skipped.
+pub(crate) fn read_points(path: &Path) -> Result<Vec<[f64; 4]>> {
+    let contents = std::fs::read_to_string(path)?;
+    let mut points = Vec::new();
+    let mut first = true;
+
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let header = std::mem::replace(&mut first, false);
+
+        let values: Result<Vec<f64>, _> = line.split(',').map(|v| v.trim().parse()).collect();
+
+        match values {
+            Ok(values) if values.len() == 4 => {
+                points.push([values[0], values[1], values[2], values[3]])
+            }
+            Ok(values) => bail!(
+                "Expected 4 values on line {} of {}, got {} ({:?})",
+                number + 1,
+                path.display(),
+                values.len(),
+                values
+            ),
+            // Allow a header in the first line that isn't empty or a comment
+            Err(_) if header => continue,
+            Err(e) => bail!(
+                "Invalid number on line {} of {}: {}",
+                number + 1,
+                path.display(),
+                e
+            ),
+        }
+    }
+
+    Ok(points)
+}
+
+/// Whether any three of the `points` lie on a single line
+fn has_collinear_triple(points: &[[f64; 2]; 4]) -> bool {
+    (0..4).any(|skip| {
+        let [a, b, c]: [[f64; 2]; 3] =
+            std::array::from_fn(|i| points[if i < skip { i } else { i + 1 }]);
+        let cross = (b[0] - a[0]) * (c[1] - a[1]) - (b[1] - a[1]) * (c[0] - a[0]);
+
+        cross.abs() < 1e-6
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render;
+
+    /// A dozen points spread over a 4000x3000 image, and where the affine transformation
+    /// `x' = 0.9 x - 0.2 y + 150`, `y' = 0.3 x + 1.1 y - 40` moves them
+    fn control_points() -> (Vec<[f64; 2]>, Vec<[f64; 2]>) {
+        let from: Vec<[f64; 2]> = (0..12)
+            .map(|i| {
+                [
+                    (i % 4) as f64 * 1300.0 + 17.0,
+                    (i / 4) as f64 * 1400.0 + 31.0,
+                ]
+            })
+            .collect();
+        let to = from
+            .iter()
+            .map(|&[x, y]| [0.9 * x - 0.2 * y + 150.0, 0.3 * x + 1.1 * y - 40.0])
+            .collect();
+
+        (from, to)
+    }
+
+    #[test]
+    fn maps_the_corners_exactly() {
+        let from = [[0.0, 0.0], [3999.0, 0.0], [3999.0, 2999.0], [0.0, 2999.0]];
+        let to = [
+            [112.5, 80.0],
+            [3870.0, 240.0],
+            [3650.0, 2790.0],
+            [300.0, 2950.0],
+        ];
+
+        let matrix = homography(&from, &to).unwrap();
+
+        for (&[x, y], &[new_x, new_y]) in from.iter().zip(&to) {
+            let (found_x, found_y) = render::transform_point(&matrix, x as f32, y as f32).unwrap();
+            assert!(
+                (found_x as f64 - new_x).abs() < 1e-3 && (found_y as f64 - new_y).abs() < 1e-3,
+                "({x}, {y}) went to ({found_x}, {found_y}) instead of ({new_x}, {new_y})"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_collinear_corners() {
+        let square = [[0.0, 0.0], [10.0, 0.0], [10.0, 10.0], [0.0, 10.0]];
+        let line = [[0.0, 0.0], [5.0, 5.0], [10.0, 10.0], [0.0, 10.0]];
+
+        assert!(homography(&square, &line).is_err());
+        assert!(homography(&line, &square).is_err());
+    }
+
+    #[test]
+    fn fits_every_model_to_exact_points() {
+        let (from, to) = control_points();
+
+        let fit = fit(Model::Affine, &from, &to, None).unwrap();
+        assert!(fit.rms() < 1e-6, "{}", fit.rms());
+        assert!(fit.inliers.iter().all(|&inlier| inlier));
+
+        let expected = [[0.9, -0.2, 150.0], [0.3, 1.1, -40.0], [0.0, 0.0, 1.0]];
+        for (row, expected) in fit.matrix.rows().into_iter().zip(expected) {
+            for (&value, expected) in row.iter().zip(expected) {
+                assert!((value - expected).abs() < 1e-3, "{value} != {expected}");
+            }
+        }
+
+        // The affine transformation is a special perspective one, but not a similarity
+        let projective = super::fit(Model::Projective, &from, &to, None).unwrap();
+        assert!(projective.rms() < 1e-6, "{}", projective.rms());
+
+        let similarity = super::fit(Model::Similarity, &from, &to, None).unwrap();
+        assert!(similarity.rms() > 10.0, "{}", similarity.rms());
+    }
+
+    #[test]
+    fn rejects_outliers_with_ransac() {
+        let (from, mut to) = control_points();
+        to[5][0] += 60.0;
+        to[5][1] -= 25.0;
+
+        let plain = fit(Model::Affine, &from, &to, None).unwrap();
+        assert!(plain.rms() > 5.0, "{}", plain.rms());
+
+        let robust = fit(Model::Affine, &from, &to, Some(1.0)).unwrap();
+        for (i, &inlier) in robust.inliers.iter().enumerate() {
+            assert_eq!(inlier, i != 5, "point {i}");
+        }
+        assert!(robust.rms() < 1e-6, "{}", robust.rms());
+        assert!(
+            (robust.residuals[5] - 65.0).abs() < 1e-3,
+            "{}",
+            robust.residuals[5]
+        );
+    }
+
+    #[test]
+    fn needs_enough_points() {
+        let (from, to) = control_points();
+
+        assert!(fit(Model::Projective, &from[3..6], &to[3..6], None).is_err());
+        assert!(fit(Model::Affine, &from[3..6], &to[3..6], None).is_ok());
+    }
+
+    #[test]
+    fn skips_comments_and_a_header() {
+        let path =
+            std::env::temp_dir().join(format!("mxtransform-points-{}.csv", std::process::id()));
+        let read = |contents: &str| {
+            std::fs::write(&path, contents).unwrap();
+            let points = read_points(&path);
+            std::fs::remove_file(&path).unwrap();
+            points
+        };
+
+        let points =
+            read("# Corners of the page\n\nx,y,x',y'\n1,2,3,4\n# The other one\n5, 6, 7, 8\n");
+        assert_eq!(
+            points.unwrap(),
+            [[1.0, 2.0, 3.0, 4.0], [5.0, 6.0, 7.0, 8.0]]
+        );
+
+        let points = read("x,y,x',y'\n1,2,3,4\n");
+        assert_eq!(points.unwrap(), [[1.0, 2.0, 3.0, 4.0]]);
+
+        // Only the first line can be a header
+        let error = read("# Corners\n1,2,3,4\nx,y,x',y'\n").unwrap_err();
+        assert!(error.to_string().contains("line 3"), "{error}");
+
+        let error = read("x,y,x',y'\nx,y,x',y'\n").unwrap_err();
+        assert!(error.to_string().contains("line 2"), "{error}");
+    }
+}
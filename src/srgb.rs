@@ -0,0 +1,125 @@
+use std::sync::LazyLock;
+
+/// The amount of steps the table converting linear light back to sRGB has, which is enough to
+/// keep 16-bit samples within a tenth of a step of the exact curve
+const STEPS: usize = 1 << 16;
+
+/// The sRGB encoded values of all 8-bit samples in linear light, scaled to 0-255
+pub(crate) static TO_LINEAR_8: LazyLock<Vec<f32>> = LazyLock::new(|| to_linear_table(255));
+
+/// The sRGB encoded values of all 16-bit samples in linear light, scaled to 0-65535
+pub(crate) static TO_LINEAR_16: LazyLock<Vec<f32>> = LazyLock::new(|| to_linear_table(65535));
+
+/// The sRGB encoded values of evenly spaced amounts of linear light between 0 and 1
+static TO_SRGB: LazyLock<Vec<f32>> = LazyLock::new(|| {
+    (0..=STEPS)
+        .map(|step| encode(step as f32 / STEPS as f32))
+        .collect()
+});
+
+fn to_linear_table(max: usize) -> Vec<f32> {
+    (0..=max)
+        .map(|value| decode(value as f32 / max as f32) * max as f32)
+        .collect()
+}
+
+/// Converts an sRGB encoded value between 0 and 1 into linear light
+fn decode(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Converts linear light between 0 and 1 into an sRGB encoded value
+fn encode(value: f32) -> f32 {
+    if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Converts linear light between 0 and 1 into an sRGB encoded value, interpolating between the
+/// steps of the table and clamping values outside of that range
+pub(crate) fn to_srgb(value: f32) -> f32 {
+    let position = value.clamp(0.0, 1.0) * STEPS as f32;
+    let step = (position as usize).min(STEPS - 1);
+    let fraction = position - step as f32;
+
+    TO_SRGB[step] + (TO_SRGB[step + 1] - TO_SRGB[step]) * fraction
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::images::Sample;
+
+    /// The sRGB curve in double precision, from encoded values to linear light
+    fn exact_decode(value: f64) -> f64 {
+        if value <= 0.04045 {
+            value / 12.92
+        } else {
+            ((value + 0.055) / 1.055).powf(2.4)
+        }
+    }
+
+    /// The sRGB curve in double precision, from linear light to encoded values
+    fn exact_encode(value: f64) -> f64 {
+        if value <= 0.0031308 {
+            value * 12.92
+        } else {
+            1.055 * value.powf(1.0 / 2.4) - 0.055
+        }
+    }
+
+    #[test]
+    fn round_trips_every_sample() {
+        for value in 0..=u8::MAX {
+            assert_eq!(u8::from_linear(value.to_linear()), value);
+        }
+
+        for value in 0..=u16::MAX {
+            assert_eq!(u16::from_linear(value.to_linear()), value);
+        }
+    }
+
+    #[test]
+    fn follows_the_curve_around_the_knee() {
+        // The knee lies between the 8-bit samples 10 and 11, and the 16-bit ones 2650 and 2651
+        for (table, max, values) in [
+            (&TO_LINEAR_8, 255.0, [0, 1, 10, 11, 12, 128, 255]),
+            (
+                &TO_LINEAR_16,
+                65535.0,
+                [0, 1, 2650, 2651, 2652, 32768, 65535],
+            ),
+        ] {
+            for value in values {
+                let exact = exact_decode(value as f64 / max) * max;
+                let error = (table[value] as f64 - exact).abs();
+                assert!(
+                    error <= 1e-5 * max.max(exact),
+                    "{value}: {} != {exact}",
+                    table[value]
+                );
+            }
+        }
+
+        // Encoding interpolates the table, which stays within a tenth of a 16-bit step of the
+        // curve, also at the knee where both of its parts meet
+        let tolerance = 0.1 / 65535.0;
+        let knee = exact_decode(0.04045);
+        assert!((to_srgb(knee as f32) as f64 - 0.04045).abs() < tolerance);
+
+        for step in 0..=100_000 {
+            let linear = step as f64 / 100_000.0;
+            let (srgb, exact) = (to_srgb(linear as f32) as f64, exact_encode(linear));
+            assert!(
+                (srgb - exact).abs() < tolerance,
+                "{linear}: {srgb} != {exact}"
+            );
+        }
+    }
+}
@@ -0,0 +1,1567 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use color_eyre::{
+    eyre::{bail, eyre},
+    Result,
+};
+use ndarray::Array2;
+use tiff::{
+    decoder::{Decoder as TiffDecoder, DecodingResult},
+    encoder::{colortype, TiffEncoder, TiffValue},
+    tags::{SampleFormat, Tag},
+};
+
+use crate::{
+    filter::Edge,
+    images::{self, AnyImage, Depth, Image, ImageArray, Layout, Sample},
+    plan,
+    render::{self, Sampling},
+};
+
+/// The width and height of the tiles the input image is kept in memory in
+const TILE: usize = 256;
+
+/// The size of the strips TIFF images are written in, in bytes, rounded up to whole rows. It's
+/// the size the TIFF encoder writes whole images in, so that images come out the same whether
+/// they're rendered in bands or not.
+const STRIP_BYTES: usize = 1_000_000;
+
+/// An input image that can be read one row at a time, without decoding all of it
+pub(crate) trait RowReader {
+    /// The width and height of the image
+    fn dimensions(&self) -> (usize, usize);
+
+    /// The depth (8 or 16 bits, or floating point) and the channels of the samples
+    fn layout(&self) -> Layout;
+
+    /// Reads the samples of the row `y` into `row`, in the range of the depth of the image.
+    ///
+    /// Reading the rows in order is the fastest, going back may have to decode the image again.
+    fn read_row(&mut self, y: usize, row: &mut [f32]) -> Result<()>;
+
+    /// How many bytes the reader keeps in memory
+    fn memory(&self) -> usize;
+}
+
+/// Opens an image for reading it row by row, recognizing PNG, TIFF and binary PGM/PPM images by
+/// their contents
+pub(crate) fn open(path: &Path) -> Result<Box<dyn RowReader>> {
+    let mut magic = [0; 4];
+    let read = File::open(path)?.read(&mut magic)?;
+
+    Ok(match &magic[..read] {
+        [0x89, b'P', b'N', b'G'] => Box::new(PngReader::open(path)?),
+        b"II*\0" | b"MM\0*" => Box::new(TiffReader::open(path)?),
+        [b'P', b'5' | b'6', ..] => Box::new(PnmReader::open(path)?),
+        _ => bail!("Only PNG, TIFF and binary PGM/PPM images can be read in tiles"),
+    })
+}
+
+/// Reads the whole image row by row in its own depth, for images the image crate can't decode
+pub(crate) fn read_image(reader: &mut dyn RowReader) -> Result<AnyImage> {
+    match reader.layout().depth {
+        Depth::Eight => read_rows::<u8>(reader),
+        Depth::Sixteen => read_rows::<u16>(reader),
+        Depth::Float => read_rows::<f32>(reader),
+    }
+}
+
+fn read_rows<T: Sample>(reader: &mut dyn RowReader) -> Result<AnyImage> {
+    let (width, height) = reader.dimensions();
+    let channels = reader.layout().channels;
+
+    let mut array = ImageArray::<T>::default((height, width, channels));
+    let mut row = vec![0.0; width * channels];
+    for (y, mut pixels) in array.outer_iter_mut().enumerate() {
+        reader.read_row(y, &mut row)?;
+
+        for (value, &sample) in pixels.iter_mut().zip(&row) {
+            *value = T::from_f32(sample);
+        }
+    }
+
+    Ok(T::wrap(array))
+}
+
+/// A binary PGM (P5) or PPM (P6) image with 8 or 16 bits per sample, whose rows are read
+/// directly
+struct PnmReader {
+    file: File,
+    dimensions: (usize, usize),
+    layout: Layout,
+
+    /// Where the pixel data starts in the file
+    data: u64,
+
+    /// Where the file is currently read from
+    position: u64,
+
+    samples: Vec<u8>,
+}
+
+impl PnmReader {
+    fn open(path: &Path) -> Result<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut data = 0;
+
+        let channels = match header_field(&mut reader, &mut data)?.as_str() {
+            "P5" => 1,
+            _ => 3,
+        };
+
+        let mut number = |name: &str| -> Result<usize> {
+            let field = header_field(&mut reader, &mut data)?;
+            field
+                .parse()
+                .map_err(|_| eyre!("Invalid {name} in the PNM header: {field}"))
+        };
+        let (width, height, max) = (number("width")?, number("height")?, number("maximum")?);
+
+        let depth = match max {
+            255 => Depth::Eight,
+            65535 => Depth::Sixteen,
+            _ => bail!("Only PNM images with 8 or 16 bits per sample can be read in tiles, this one has a maximum of {max}"),
+        };
+
+        Ok(PnmReader {
+            file: reader.into_inner(),
+            dimensions: (width, height),
+            layout: Layout { depth, channels },
+            data,
+            position: u64::MAX,
+            samples: vec![0; width * channels * depth.bytes()],
+        })
+    }
+}
+
+/// Reads the next whitespace-separated field of a PNM header, skipping comments and counting the
+/// bytes read in `offset`
+fn header_field(reader: &mut impl BufRead, offset: &mut u64) -> Result<String> {
+    let mut field = String::new();
+
+    loop {
+        let mut byte = [0];
+        reader.read_exact(&mut byte)?;
+        *offset += 1;
+
+        match byte[0] {
+            b'#' => {
+                *offset += reader.read_until(b'\n', &mut Vec::new())? as u64;
+                if !field.is_empty() {
+                    return Ok(field);
+                }
+            }
+            byte if byte.is_ascii_whitespace() => {
+                if !field.is_empty() {
+                    return Ok(field);
+                }
+            }
+            byte => field.push(byte as char),
+        }
+    }
+}
+
+impl RowReader for PnmReader {
+    fn dimensions(&self) -> (usize, usize) {
+        self.dimensions
+    }
+
+    fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    fn read_row(&mut self, y: usize, row: &mut [f32]) -> Result<()> {
+        let start = self.data + (y * self.samples.len()) as u64;
+        if self.position != start {
+            self.file.seek(SeekFrom::Start(start))?;
+        }
+
+        self.file.read_exact(&mut self.samples)?;
+        self.position = start + self.samples.len() as u64;
+
+        from_bytes(&self.samples, self.layout.depth, row);
+
+        Ok(())
+    }
+
+    fn memory(&self) -> usize {
+        self.samples.len()
+    }
+}
+
+/// Reads samples with 8 bits or 16 big-endian bits from `bytes` into `samples`
+fn from_bytes(bytes: &[u8], depth: Depth, samples: &mut [f32]) {
+    match depth {
+        Depth::Eight => {
+            for (sample, &byte) in samples.iter_mut().zip(bytes) {
+                *sample = byte as f32;
+            }
+        }
+        _ => {
+            for (sample, bytes) in samples.iter_mut().zip(bytes.chunks_exact(2)) {
+                *sample = u16::from_be_bytes([bytes[0], bytes[1]]) as f32;
+            }
+        }
+    }
+}
+
+/// A non-interlaced PNG image, decoded row by row
+struct PngReader {
+    path: PathBuf,
+    reader: png::Reader<BufReader<File>>,
+    layout: Layout,
+
+    /// The row that is decoded next
+    next: usize,
+
+    samples: Vec<u8>,
+}
+
+impl PngReader {
+    fn open(path: &Path) -> Result<Self> {
+        let reader = Self::decode(path)?;
+
+        let info = reader.info();
+        if info.interlaced {
+            bail!("Interlaced PNG images can't be read in tiles");
+        }
+
+        let (color, depth) = reader.output_color_type();
+        let depth = match depth {
+            png::BitDepth::Sixteen => Depth::Sixteen,
+            _ => Depth::Eight,
+        };
+
+        Ok(PngReader {
+            path: path.to_path_buf(),
+            layout: Layout {
+                depth,
+                channels: color.samples(),
+            },
+            next: 0,
+            samples: vec![0; info.width as usize * color.samples() * depth.bytes()],
+            reader,
+        })
+    }
+
+    /// Starts decoding the image from the beginning, expanding palettes and low bit depths like
+    /// the image crate
+    fn decode(path: &Path) -> Result<png::Reader<BufReader<File>>> {
+        let mut decoder = png::Decoder::new_with_limits(
+            BufReader::new(File::open(path)?),
+            png::Limits { bytes: usize::MAX },
+        );
+        decoder.set_transformations(png::Transformations::EXPAND);
+
+        Ok(decoder.read_info()?)
+    }
+}
+
+impl RowReader for PngReader {
+    fn dimensions(&self) -> (usize, usize) {
+        let info = self.reader.info();
+        (info.width as usize, info.height as usize)
+    }
+
+    fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    fn read_row(&mut self, y: usize, row: &mut [f32]) -> Result<()> {
+        if y < self.next {
+            self.reader = Self::decode(&self.path)?;
+            self.next = 0;
+        }
+
+        while self.next <= y {
+            let decoded = self
+                .reader
+                .next_row()?
+                .ok_or_else(|| eyre!("The PNG image ended before row {y}"))?;
+
+            if self.next == y {
+                self.samples.copy_from_slice(decoded.data());
+            }
+
+            self.next += 1;
+        }
+
+        from_bytes(&self.samples, self.layout.depth, row);
+
+        Ok(())
+    }
+
+    fn memory(&self) -> usize {
+        // The decoder keeps the current and the previous row around to unfilter the next one
+        self.samples.len() * 3
+    }
+}
+
+/// A TIFF image with 8-bit, 16-bit or floating point samples stored in strips or tiles, decoded
+/// one row of them at a time
+struct TiffReader {
+    decoder: TiffDecoder<BufReader<File>>,
+    dimensions: (usize, usize),
+    layout: Layout,
+
+    /// The width and height of the strips or tiles
+    chunk: (usize, usize),
+
+    /// The row of chunks held in `rows`
+    cached: Option<usize>,
+    rows: Samples,
+}
+
+/// Decoded samples of a TIFF image, kept in the type they're stored in
+enum Samples {
+    U8(Vec<u8>),
+    U16(Vec<u16>),
+    F32(Vec<f32>),
+}
+
+impl Samples {
+    fn new(depth: Depth, len: usize) -> Self {
+        match depth {
+            Depth::Eight => Samples::U8(vec![0; len]),
+            Depth::Sixteen => Samples::U16(vec![0; len]),
+            Depth::Float => Samples::F32(vec![0.0; len]),
+        }
+    }
+
+    fn decoded(result: DecodingResult) -> Result<Self> {
+        Ok(match result {
+            DecodingResult::U8(samples) => Samples::U8(samples),
+            DecodingResult::U16(samples) => Samples::U16(samples),
+            DecodingResult::F32(samples) => Samples::F32(samples),
+            _ => bail!("Only TIFF images with 8-bit, 16-bit or floating point samples can be read in tiles"),
+        })
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Samples::U8(samples) => samples.len(),
+            Samples::U16(samples) => samples.len(),
+            Samples::F32(samples) => samples.len(),
+        }
+    }
+
+    /// Copies `len` samples from `source`, starting at `from`, to `to`
+    fn copy(&mut self, to: usize, source: &Samples, from: usize, len: usize) {
+        match (self, source) {
+            (Samples::U8(samples), Samples::U8(source)) => {
+                samples[to..to + len].copy_from_slice(&source[from..from + len]);
+            }
+            (Samples::U16(samples), Samples::U16(source)) => {
+                samples[to..to + len].copy_from_slice(&source[from..from + len]);
+            }
+            (Samples::F32(samples), Samples::F32(source)) => {
+                samples[to..to + len].copy_from_slice(&source[from..from + len]);
+            }
+            _ => unreachable!("all chunks of a TIFF image have the same type of samples"),
+        }
+    }
+
+    /// Converts the samples starting at `start` into `row`
+    fn read(&self, start: usize, row: &mut [f32]) {
+        let end = start + row.len();
+
+        match self {
+            Samples::U8(samples) => {
+                for (value, &sample) in row.iter_mut().zip(&samples[start..end]) {
+                    *value = sample as f32;
+                }
+            }
+            Samples::U16(samples) => {
+                for (value, &sample) in row.iter_mut().zip(&samples[start..end]) {
+                    *value = sample as f32;
+                }
+            }
+            Samples::F32(samples) => row.copy_from_slice(&samples[start..end]),
+        }
+    }
+}
+
+impl TiffReader {
+    fn open(path: &Path) -> Result<Self> {
+        let mut decoder = TiffDecoder::new(BufReader::new(File::open(path)?))?;
+
+        let (width, height) = decoder.dimensions()?;
+        let (channels, bits) = match decoder.colortype()? {
+            tiff::ColorType::Gray(bits @ (8 | 16 | 32)) => (1, bits),
+            tiff::ColorType::GrayA(bits @ (8 | 16 | 32)) => (2, bits),
+            tiff::ColorType::RGB(bits @ (8 | 16 | 32)) => (3, bits),
+            tiff::ColorType::RGBA(bits @ (8 | 16 | 32)) => (4, bits),
+            color => bail!("TIFF images with the color type {color:?} can't be read in tiles"),
+        };
+        let float = decoder
+            .find_tag_unsigned_vec::<u16>(Tag::SampleFormat)?
+            .is_some_and(|formats| formats.contains(&SampleFormat::IEEEFP.to_u16()));
+        let depth = match bits {
+            8 => Depth::Eight,
+            16 => Depth::Sixteen,
+            _ if float => Depth::Float,
+            _ => bail!("TIFF images with 32-bit integer samples can't be read in tiles"),
+        };
+
+        let (chunk_width, chunk_height) = decoder.chunk_dimensions();
+
+        Ok(TiffReader {
+            decoder,
+            dimensions: (width as usize, height as usize),
+            layout: Layout { depth, channels },
+            chunk: (chunk_width as usize, chunk_height as usize),
+            cached: None,
+            rows: Samples::new(depth, 0),
+        })
+    }
+
+    /// The amount of chunks along the X axis
+    fn across(&self) -> usize {
+        self.dimensions.0.div_ceil(self.chunk.0)
+    }
+
+    /// Decodes the `index`th row of chunks into `rows`
+    fn decode(&mut self, index: usize) -> Result<()> {
+        let (width, _) = self.dimensions;
+        let (chunk_width, chunk_height) = self.chunk;
+        let channels = self.layout.channels;
+        let across = self.across();
+
+        for column in 0..across {
+            let chunk = (index * across + column) as u32;
+            let (data_width, data_height) = self.decoder.chunk_data_dimensions(chunk);
+            let (data_width, data_height) = (data_width as usize, data_height as usize);
+
+            let samples = Samples::decoded(self.decoder.read_chunk(chunk)?)?;
+            if std::mem::discriminant(&samples) != std::mem::discriminant(&self.rows) {
+                bail!("Only TIFF images with the same type of samples in every chunk can be read in tiles");
+            }
+
+            let row_samples = data_width * channels;
+            if samples.len() < data_height * row_samples {
+                bail!("Only TIFF images with interleaved channels can be read in tiles");
+            }
+
+            // Strips are rows of the image already, so they're kept as they are
+            if across == 1 {
+                self.rows = samples;
+                break;
+            }
+
+            if self.rows.len() != chunk_height * width * channels {
+                self.rows = Samples::new(self.layout.depth, chunk_height * width * channels);
+            }
+
+            for y in 0..data_height {
+                let start = (y * width + column * chunk_width) * channels;
+                self.rows
+                    .copy(start, &samples, y * row_samples, row_samples);
+            }
+        }
+
+        self.cached = Some(index);
+
+        Ok(())
+    }
+}
+
+impl RowReader for TiffReader {
+    fn dimensions(&self) -> (usize, usize) {
+        self.dimensions
+    }
+
+    fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    fn read_row(&mut self, y: usize, row: &mut [f32]) -> Result<()> {
+        let (width, _) = self.dimensions;
+        let (_, chunk_height) = self.chunk;
+        let row_samples = width * self.layout.channels;
+
+        let index = y / chunk_height;
+        if self.cached != Some(index) {
+            self.decode(index)?;
+        }
+
+        let start = (y % chunk_height) * row_samples;
+        self.rows.read(start, row);
+
+        Ok(())
+    }
+
+    fn memory(&self) -> usize {
+        let (width, _) = self.dimensions;
+        let (chunk_width, chunk_height) = self.chunk;
+        let bytes = self.layout.bytes_per_pixel();
+
+        // Tiles are decoded one at a time next to the row of them, strips are kept as they are
+        let chunk = match self.across() {
+            1 => 0,
+            _ => chunk_width * chunk_height * bytes,
+        };
+
+        chunk_height * width * bytes + chunk
+    }
+}
+
+/// The tiles of the input image that are read by the band of the output image being rendered,
+/// the other tiles are left out
+struct Tiles<T> {
+    dimensions: (usize, usize),
+    channels: usize,
+
+    /// The amount of tiles along the X axis
+    columns: usize,
+
+    tiles: Vec<Option<Box<[T]>>>,
+}
+
+impl<T: Sample> Image<T> for Tiles<T> {
+    fn dimensions(&self) -> (usize, usize) {
+        self.dimensions
+    }
+
+    fn pixel(&self, x: usize, y: usize) -> [T; 4] {
+        let tile = self.tiles[y / TILE * self.columns + x / TILE]
+            .as_deref()
+            .expect("the tiles read by a band are loaded before rendering it");
+
+        let channels = self.channels;
+        let start = ((y % TILE) * TILE + x % TILE) * channels;
+        images::to_rgba(channels, |channel| tile[start + channel])
+    }
+}
+
+/// Whether the convex quadrilateral with the `corners` (in order around it) overlaps the
+/// rectangle from `min` to `max`
+fn overlaps(corners: &[(f64, f64); 4], min: (f64, f64), max: (f64, f64)) -> bool {
+    let rectangle = [min, (max.0, min.1), max, (min.0, max.1)];
+
+    // By the separating axis theorem, two convex shapes don't overlap if and only if their
+    // projections onto the normal of one of their edges don't overlap
+    let normals = (0..4)
+        .map(|i| {
+            let (a, b) = (corners[i], corners[(i + 1) % 4]);
+            (b.1 - a.1, a.0 - b.0)
+        })
+        .chain([(1.0, 0.0), (0.0, 1.0)]);
+
+    for (normal_x, normal_y) in normals {
+        let project = |points: &[(f64, f64); 4]| {
+            points
+                .iter()
+                .map(|(x, y)| normal_x * x + normal_y * y)
+                .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), v| {
+                    (min.min(v), max.max(v))
+                })
+        };
+
+        let (corners, rectangle) = (project(corners), project(&rectangle));
+        if corners.1 < rectangle.0 || rectangle.1 < corners.0 {
+            return false;
+        }
+    }
+
+    true
+}
+
+/// Finds the tiles of a `width`x`height` input image that rendering the output rows from
+/// `first_row` to `last_row` (exclusive) reads, as a mask over all tiles.
+///
+/// The rows cover a rectangle of the output image, which the inverse maps onto a convex
+/// quadrilateral in the input image. Every tile within the reach of the filter around it is
+/// needed, also through the edges of the image if they repeat it.
+fn needed_tiles(
+    inverse: &Array2<f32>,
+    (width, height): (usize, usize),
+    (first_row, last_row): (usize, usize),
+    (out_width, out_height): (usize, usize),
+    sampling: &Sampling,
+) -> Vec<bool> {
+    let (columns, rows) = (width.div_ceil(TILE), height.div_ceil(TILE));
+    let all = vec![true; columns * rows];
+
+    // The edges of the output pixels, which also contain all of their supersamples
+    let (left, right) = (-0.5, out_width as f32 - 0.5);
+    let (bottom, top) = (
+        (out_height - last_row) as f32 - 0.5,
+        (out_height - first_row) as f32 - 0.5,
+    );
+    let out_corners = [(left, bottom), (right, bottom), (right, top), (left, top)];
+
+    let mut corners = [(0.0, 0.0); 4];
+    for (corner, &(x, y)) in corners.iter_mut().zip(&out_corners) {
+        // The band is unbounded in the input image if it crosses the horizon
+        let Some((src_x, src_y)) = render::transform_point(inverse, x, y) else {
+            return all;
+        };
+
+        *corner = (src_x as f64, (height - 1) as f64 - src_y as f64);
+    }
+
+    // One more pixel covers rounding to the closest pixel and rounding errors
+    let (reach_x, reach_y) = render::reach(inverse, &out_corners, sampling);
+    let (reach_x, reach_y) = (reach_x + 1.0, reach_y + 1.0);
+
+    let (mut min_x, mut max_x) = (f64::INFINITY, f64::NEG_INFINITY);
+    let (mut min_y, mut max_y) = (f64::INFINITY, f64::NEG_INFINITY);
+    for (x, y) in corners {
+        (min_x, max_x) = (min_x.min(x - reach_x), max_x.max(x + reach_x));
+        (min_y, max_y) = (min_y.min(y - reach_y), max_y.max(y + reach_y));
+    }
+
+    let (w, h) = (width as f64, height as f64);
+    match sampling.border.edge {
+        // Nothing is read outside of the image
+        Edge::Transparent | Edge::Background => {
+            (min_x, max_x) = (min_x.max(0.0), max_x.min(w - 1.0));
+            (min_y, max_y) = (min_y.max(0.0), max_y.min(h - 1.0));
+        }
+        // Footprints never read further than one image size beyond the edges, but single
+        // samples can land anywhere in a repeated image, which may read all of it
+        Edge::Clamp | Edge::Repeat | Edge::Mirror => {
+            if min_x < -w || min_y < -h || max_x >= 2.0 * w || max_y >= 2.0 * h {
+                return all;
+            }
+        }
+    }
+
+    // The tiles are laid out over the image and its surroundings, and the pixels of the ones
+    // that are read are mapped into the image like the border does
+    let tile_range = |min: f64, max: f64| {
+        (min.floor() as isize).div_euclid(TILE as isize)
+            ..=(max.ceil() as isize).div_euclid(TILE as isize)
+    };
+    let mapped = |tile: isize, len: usize| {
+        let mut tiles: Vec<usize> = (tile * TILE as isize..(tile + 1) * TILE as isize)
+            .filter_map(|i| sampling.border.index(i, len))
+            .map(|i| i / TILE)
+            .collect();
+        tiles.sort_unstable();
+        tiles.dedup();
+        tiles
+    };
+
+    let mut needed = vec![false; columns * rows];
+
+    for tile_y in tile_range(min_y, max_y) {
+        let tile_rows = mapped(tile_y, height);
+        if tile_rows.is_empty() {
+            continue;
+        }
+
+        for tile_x in tile_range(min_x, max_x) {
+            let (x, y) = (
+                (tile_x * TILE as isize) as f64,
+                (tile_y * TILE as isize) as f64,
+            );
+            let min = (x - reach_x, y - reach_y);
+            let max = (
+                x + (TILE - 1) as f64 + reach_x,
+                y + (TILE - 1) as f64 + reach_y,
+            );
+            if !overlaps(&corners, min, max) {
+                continue;
+            }
+
+            for column in mapped(tile_x, width) {
+                for &row in &tile_rows {
+                    needed[row * columns + column] = true;
+                }
+            }
+        }
+    }
+
+    needed
+}
+
+/// The output image rendered in bands
+pub(crate) struct Output<'a> {
+    pub(crate) path: &'a Path,
+    pub(crate) dimensions: (usize, usize),
+    pub(crate) layout: Layout,
+
+    /// The RGBA color of the pixels that nothing is rendered into
+    pub(crate) background: [u8; 4],
+}
+
+/// Renders the output image in bands of rows in gather mode and streams them into the `output`
+/// file (PNG, TIFF or PAM), keeping only the tiles of the input image that the current band reads
+/// in memory.
+///
+/// The bands are made as tall as the memory `budget` (in bytes) allows. Returns the average
+/// amount of input pixels read per output pixel and the layout the image was written in.
+pub(crate) fn render(
+    reader: &mut dyn RowReader,
+    output: &Output,
+    inverse: &Array2<f32>,
+    sampling: &Sampling,
+    budget: usize,
+) -> Result<(f32, Layout)> {
+    // The input image is rendered in its own depth, and only converted when writing the bands
+    match reader.layout().depth {
+        Depth::Eight => render_bands::<u8>(reader, output, inverse, sampling, budget),
+        Depth::Sixteen => render_bands::<u16>(reader, output, inverse, sampling, budget),
+        Depth::Float => render_bands::<f32>(reader, output, inverse, sampling, budget),
+    }
+}
+
+fn render_bands<T: Sample>(
+    reader: &mut dyn RowReader,
+    output: &Output,
+    inverse: &Array2<f32>,
+    sampling: &Sampling,
+    budget: usize,
+) -> Result<(f32, Layout)> {
+    let (width, height) = reader.dimensions();
+    let (out_width, out_height) = output.dimensions;
+    let (columns, rows) = (width.div_ceil(TILE), height.div_ceil(TILE));
+    let channels = reader.layout().channels;
+    let out_channels = output.layout.channels;
+
+    let mut tiles = Tiles::<T> {
+        dimensions: (width, height),
+        channels,
+        columns,
+        tiles: vec![None; columns * rows],
+    };
+    let tile_samples = TILE * TILE * channels;
+    let tile_bytes = tile_samples * size_of::<T>();
+
+    // Everything besides the tiles and the band, with a TIFF strip that's up to a row of up to
+    // 4 floating point samples larger
+    let strip = STRIP_BYTES + output.dimensions.0 * 4 * size_of::<f32>();
+    let overhead = reader.memory() + width * channels * size_of::<f32>() + strip;
+
+    let row_bytes = out_width * out_channels * size_of::<T>();
+
+    let needed = |(first_row, last_row)| {
+        needed_tiles(
+            inverse,
+            (width, height),
+            (first_row, last_row),
+            (out_width, out_height),
+            sampling,
+        )
+    };
+
+    // All bands are planned before the output image is created, so that it isn't left behind
+    // half written if the budget is too small
+    let bands = plan_bands(
+        out_height,
+        (row_bytes, tile_bytes),
+        (budget, overhead),
+        needed,
+    )?;
+
+    let typed = sampling.convert::<T>();
+
+    write_image(output.path, output.dimensions, output.layout, |write| {
+        let pb = render::progress_bar(out_width * out_height);
+        let mut row = vec![0.0; width * channels];
+        let mut cost = 0.0;
+
+        for &(first_row, last_row) in &bands {
+            let needed = needed((first_row, last_row));
+
+            // Drop the tiles that aren't needed anymore before loading the new ones
+            for (tile, &needed) in tiles.tiles.iter_mut().zip(&needed) {
+                if !needed {
+                    *tile = None;
+                }
+            }
+
+            for tile_row in 0..rows {
+                let missing: Vec<usize> = (0..columns)
+                    .filter(|&column| {
+                        let index = tile_row * columns + column;
+                        needed[index] && tiles.tiles[index].is_none()
+                    })
+                    .collect();
+                if missing.is_empty() {
+                    continue;
+                }
+
+                let mut loaded: Vec<Box<[T]>> = missing
+                    .iter()
+                    .map(|_| vec![T::default(); tile_samples].into_boxed_slice())
+                    .collect();
+
+                for y in tile_row * TILE..((tile_row + 1) * TILE).min(height) {
+                    reader.read_row(y, &mut row)?;
+
+                    let offset = (y % TILE) * TILE * channels;
+                    for (&column, tile) in missing.iter().zip(&mut loaded) {
+                        let (start, end) = (column * TILE, ((column + 1) * TILE).min(width));
+                        let samples = &row[start * channels..end * channels];
+
+                        // The samples are already in the depth of the tiles
+                        for (value, &sample) in tile[offset..].iter_mut().zip(samples) {
+                            *value = T::from_f32(sample);
+                        }
+                    }
+                }
+
+                for (column, tile) in missing.into_iter().zip(loaded) {
+                    tiles.tiles[tile_row * columns + column] = Some(tile);
+                }
+            }
+
+            let mut band: ImageArray<T> = images::filled(
+                (out_width, last_row - first_row),
+                out_channels,
+                output.background,
+            );
+
+            let band_cost = render::gather(
+                &tiles, &mut band, first_row, out_height, inverse, &typed, &pb,
+            );
+            cost += band_cost as f64 * (last_row - first_row) as f64;
+
+            write(band.as_slice().expect("new arrays are in standard layout"))?;
+        }
+
+        pb.finish();
+
+        Ok((cost / out_height as f64) as f32)
+    })
+}
+
+/// Splits the `out_height` rows of the output image into bands from their first to their last
+/// row (exclusive), as tall as the memory `budget` allows after the `overhead`.
+///
+/// A band takes `row_bytes` for every row and `tile_bytes` for every tile of the input image it
+/// reads, which are `needed` by the rows. Bands are halved until they fit, and grow again when
+/// they leave most of the budget unused.
+fn plan_bands(
+    out_height: usize,
+    (row_bytes, tile_bytes): (usize, usize),
+    (budget, overhead): (usize, usize),
+    needed: impl Fn((usize, usize)) -> Vec<bool>,
+) -> Result<Vec<(usize, usize)>> {
+    let available = budget.saturating_sub(overhead);
+    let tallest = (available / 2 / row_bytes).clamp(1, out_height);
+    let mut band_height = tallest;
+
+    let mut bands = Vec::new();
+    let mut first_row = 0;
+    while first_row < out_height {
+        // Shrink the band until the tiles it reads fit into the budget
+        let (last_row, used) = loop {
+            let last_row = (first_row + band_height).min(out_height);
+
+            let tiles = needed((first_row, last_row)).iter().filter(|&&n| n).count();
+            let used = tiles * tile_bytes + (last_row - first_row) * row_bytes;
+            if used <= available {
+                break (last_row, used);
+            }
+
+            if band_height == 1 {
+                bail!(
+                    "The memory budget of {} is too small, rendering row {first_row} alone takes {}",
+                    plan::bytes(budget as u128),
+                    plan::bytes((used + overhead) as u128)
+                );
+            }
+
+            band_height /= 2;
+        };
+        bands.push((first_row, last_row));
+
+        // Let the next band grow again if this one left most of the budget unused
+        if used <= available / 4 {
+            band_height = (band_height * 2).min(tallest);
+        }
+
+        first_row = last_row;
+    }
+
+    Ok(bands)
+}
+
+/// Converts rows of pixels with `from` channels into samples of another type with `to` channels
+fn convert_rows<T: Sample, S: Sample>(rows: &[T], from: usize, to: usize, samples: &mut Vec<S>) {
+    samples.clear();
+
+    if from == to {
+        samples.extend(rows.iter().map(|sample| sample.convert::<S>()));
+        return;
+    }
+
+    for pixel in rows.chunks_exact(from) {
+        let rgba = images::to_rgba(from, |channel| pixel[channel]);
+        let pixel = images::from_rgba(rgba, to);
+        samples.extend(pixel[..to].iter().map(|sample| sample.convert::<S>()));
+    }
+}
+
+/// Converts rows of samples into 8-bit or 16-bit big-endian bytes
+fn to_bytes<T: Sample>(rows: &[T], depth: Depth, bytes: &mut Vec<u8>) {
+    bytes.clear();
+
+    match depth {
+        Depth::Eight => bytes.extend(rows.iter().map(|sample| sample.convert::<u8>())),
+        _ => bytes.extend(
+            rows.iter()
+                .flat_map(|sample| sample.convert::<u16>().to_be_bytes()),
+        ),
+    }
+}
+
+/// The lowercase extension of `path`, which decides the format images are written in
+fn extension(path: &Path) -> String {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default()
+        .to_lowercase()
+}
+
+/// Whether [`write_image`] can write images with floating point samples at `path`, which is only
+/// the case for TIFF images
+pub(crate) fn writes_float(path: &Path) -> bool {
+    matches!(extension(path).as_str(), "tif" | "tiff")
+}
+
+/// Creates an image with the `layout` at `path` (PNG, TIFF, PAM or binary PGM/PPM, by its
+/// extension) and lets `render` write its rows into it from top to bottom.
+///
+/// The image is written next to `path` and only takes its place once it's complete. Returns the
+/// layout the image was written in, which only has more channels if the format can't store the
+/// `layout`.
+pub(crate) fn write_image<T: Sample, R>(
+    path: &Path,
+    dimensions: (usize, usize),
+    layout: Layout,
+    render: impl FnOnce(&mut dyn FnMut(&[T]) -> Result<()>) -> Result<R>,
+) -> Result<(R, Layout)> {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".part");
+    let partial = PathBuf::from(partial);
+
+    match write_format(path, &partial, dimensions, layout, render) {
+        Ok(written) => {
+            std::fs::rename(&partial, path)?;
+            Ok(written)
+        }
+        Err(e) => {
+            // Nothing may have been created yet
+            let _ = std::fs::remove_file(&partial);
+            Err(e)
+        }
+    }
+}
+
+/// Writes the image like [`write_image`] into the file at `partial`, in the format of `path`
+fn write_format<T: Sample, R>(
+    path: &Path,
+    partial: &Path,
+    (width, height): (usize, usize),
+    layout: Layout,
+    render: impl FnOnce(&mut dyn FnMut(&[T]) -> Result<()>) -> Result<R>,
+) -> Result<(R, Layout)> {
+    let extension = extension(path);
+    if layout.depth == Depth::Float && !writes_float(path) {
+        bail!("Only TIFF images can be written with floating point samples in bands");
+    }
+
+    let file = || -> Result<BufWriter<File>> { Ok(BufWriter::new(File::create(partial)?)) };
+
+    match extension.as_str() {
+        "png" => {
+            let mut encoder = png::Encoder::new(file()?, width as u32, height as u32);
+            encoder.set_color(match layout.channels {
+                1 => png::ColorType::Grayscale,
+                2 => png::ColorType::GrayscaleAlpha,
+                3 => png::ColorType::Rgb,
+                _ => png::ColorType::Rgba,
+            });
+            encoder.set_depth(match layout.depth {
+                Depth::Eight => png::BitDepth::Eight,
+                _ => png::BitDepth::Sixteen,
+            });
+            // The settings the PNG encoder of the image crate uses. Its fast compression is only
+            // implemented for whole images though, so images rendered in bands come out a little
+            // different, with the same pixels.
+            encoder.set_compression(png::Compression::Fast);
+            encoder.set_filter(png::FilterType::Sub);
+            encoder.set_adaptive_filter(png::AdaptiveFilterType::Adaptive);
+
+            let mut stream = encoder.write_header()?.into_stream_writer()?;
+            let mut bytes = Vec::new();
+            let result = render(&mut |rows| {
+                to_bytes(rows, layout.depth, &mut bytes);
+                Ok(stream.write_all(&bytes)?)
+            })?;
+            stream.finish()?;
+
+            Ok((result, layout))
+        }
+        "tif" | "tiff" => {
+            let from = layout.channels;
+
+            // TIFF images can't have gray with alpha, so they are written as RGBA
+            let layout = Layout {
+                channels: if from == 2 { 4 } else { from },
+                ..layout
+            };
+
+            let file = file()?;
+            let result = match (layout.depth, layout.channels) {
+                (Depth::Float, 1) => {
+                    write_tiff::<colortype::Gray32Float, _, _>(file, (width, height), from, render)
+                }
+                (Depth::Float, 3) => {
+                    write_tiff::<colortype::RGB32Float, _, _>(file, (width, height), from, render)
+                }
+                (Depth::Float, _) => {
+                    write_tiff::<colortype::RGBA32Float, _, _>(file, (width, height), from, render)
+                }
+                (Depth::Eight, 1) => {
+                    write_tiff::<colortype::Gray8, _, _>(file, (width, height), from, render)
+                }
+                (Depth::Eight, 3) => {
+                    write_tiff::<colortype::RGB8, _, _>(file, (width, height), from, render)
+                }
+                (Depth::Eight, _) => {
+                    write_tiff::<colortype::RGBA8, _, _>(file, (width, height), from, render)
+                }
+                (_, 1) => {
+                    write_tiff::<colortype::Gray16, _, _>(file, (width, height), from, render)
+                }
+                (_, 3) => write_tiff::<colortype::RGB16, _, _>(file, (width, height), from, render),
+                (_, _) => {
+                    write_tiff::<colortype::RGBA16, _, _>(file, (width, height), from, render)
+                }
+            }?;
+
+            Ok((result, layout))
+        }
+        "pam" | "pnm" | "ppm" | "pgm" => {
+            let max = match layout.depth {
+                Depth::Eight => u8::MAX as u16,
+                _ => u16::MAX,
+            };
+
+            let header = if extension == "pam" {
+                let (depth, tuple_type) = match layout.channels {
+                    1 => (1, "GRAYSCALE"),
+                    2 => (2, "GRAYSCALE_ALPHA"),
+                    3 => (3, "RGB"),
+                    _ => (4, "RGB_ALPHA"),
+                };
+
+                format!("P7\nWIDTH {width}\nHEIGHT {height}\nDEPTH {depth}\nMAXVAL {max}\nTUPLTYPE {tuple_type}\nENDHDR\n")
+            } else {
+                if layout.has_alpha() {
+                    bail!("PGM and PPM images can't be transparent, give the output image an opaque --background or write a PAM image instead");
+                }
+
+                let magic = if layout.has_color() { "P6" } else { "P5" };
+                format!("{magic}\n{width} {height}\n{max}\n")
+            };
+
+            let mut file = file()?;
+            file.write_all(header.as_bytes())?;
+
+            let mut bytes = Vec::new();
+            let result = render(&mut |rows| {
+                to_bytes(rows, layout.depth, &mut bytes);
+                Ok(file.write_all(&bytes)?)
+            })?;
+            file.flush()?;
+
+            Ok((result, layout))
+        }
+        _ => bail!("Only PNG, TIFF, PAM and binary PGM/PPM images can be written in bands"),
+    }
+}
+
+/// Writes a TIFF image with the color type `C` into `file` in strips, converting the rows of
+/// pixels with `from` channels that `render` writes
+fn write_tiff<C, T, R>(
+    file: BufWriter<File>,
+    (width, height): (usize, usize),
+    from: usize,
+    render: impl FnOnce(&mut dyn FnMut(&[T]) -> Result<()>) -> Result<R>,
+) -> Result<R>
+where
+    C: colortype::ColorType,
+    C::Inner: Sample,
+    [C::Inner]: TiffValue,
+    T: Sample,
+{
+    let channels = C::BITS_PER_SAMPLE.len();
+
+    let mut encoder = TiffEncoder::new(file)?;
+    let mut image = encoder.new_image::<C>(width as u32, height as u32)?;
+
+    let strip_rows = STRIP_BYTES.div_ceil(width * channels * size_of::<C::Inner>());
+    let strip_samples = strip_rows * width * channels;
+    image.rows_per_strip(strip_rows as u32)?;
+
+    // The rows are collected into strips, only the last one may be shorter
+    let mut strip = Vec::with_capacity(strip_samples);
+    let mut samples = Vec::new();
+    let result = render(&mut |rows| {
+        convert_rows(rows, from, channels, &mut samples);
+
+        let mut rows = &samples[..];
+        while !rows.is_empty() {
+            let (taken, rest) = rows.split_at((strip_samples - strip.len()).min(rows.len()));
+            strip.extend_from_slice(taken);
+            rows = rest;
+
+            if strip.len() == strip_samples {
+                image.write_strip(&strip)?;
+                strip.clear();
+            }
+        }
+
+        Ok(())
+    })?;
+
+    if !strip.is_empty() {
+        image.write_strip(&strip)?;
+    }
+    image.finish()?;
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use indicatif::ProgressBar;
+    use ndarray::array;
+
+    use super::*;
+    use crate::{
+        filter::{Antialias, Filter},
+        matrix_ext::MatrixExt,
+        render::tests::{gathered, pattern, sampling},
+        transform,
+    };
+
+    /// A directory for the images of a test, removed with everything in it at the end of the test
+    struct Scratch(PathBuf);
+
+    impl Scratch {
+        fn new(name: &str) -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("mxtransform-{name}-{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            Scratch(dir)
+        }
+
+        fn path(&self, file: &str) -> PathBuf {
+            self.0.join(file)
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Writes `array` into an image at `path` in the depth of its samples
+    fn write<T: Sample>(array: &ImageArray<T>, path: &Path) {
+        let (height, width, _) = array.dim();
+        let layout = T::wrap(array.clone()).layout();
+
+        write_image(path, (width, height), layout, |write| {
+            write(array.as_slice().unwrap())
+        })
+        .unwrap();
+    }
+
+    /// Reads the image at `path` row by row
+    fn read<T: Sample>(path: &Path) -> ImageArray<T> {
+        let mut reader = open(path).unwrap();
+        let channels = reader.layout().channels;
+
+        read_image(reader.as_mut()).unwrap().converted(channels)
+    }
+
+    /// An image that records which of its tiles are read
+    struct Recorder {
+        image: ImageArray<u8>,
+        columns: usize,
+        read: Vec<AtomicBool>,
+    }
+
+    impl Image<u8> for Recorder {
+        fn dimensions(&self) -> (usize, usize) {
+            self.image.dimensions()
+        }
+
+        fn pixel(&self, x: usize, y: usize) -> [u8; 4] {
+            self.read[y / TILE * self.columns + x / TILE].store(true, Ordering::Relaxed);
+            self.image.pixel(x, y)
+        }
+    }
+
+    /// A perspective transformation of a `width`x`height` image that keeps all of it in front of
+    /// the horizon
+    fn perspective((width, height): (usize, usize)) -> Array2<f32> {
+        let tilt = array![[1.0, 0.2, 0.0], [0.1, 1.1, 0.0], [0.0004, 0.0002, 1.0]];
+        transform::around(&tilt, (width as f32 / 2.0, height as f32 / 2.0))
+    }
+
+    #[test]
+    fn overlaps_rotated_quads() {
+        let diamond = [(10.0, 0.0), (0.0, 10.0), (-10.0, 0.0), (0.0, -10.0)];
+        let mut reversed = diamond;
+        reversed.reverse();
+
+        for (min, max, overlapping) in [
+            // Within the bounding box of the diamond, but beyond its edge
+            ((6.0, 6.0), (9.0, 9.0), false),
+            ((-9.0, -9.0), (-6.0, -6.0), false),
+            ((4.0, 4.0), (9.0, 9.0), true),
+            ((-1.0, -1.0), (1.0, 1.0), true),
+            ((-20.0, -20.0), (20.0, 20.0), true),
+            ((10.5, -1.0), (12.0, 1.0), false),
+            ((10.0, -1.0), (12.0, 1.0), true),
+        ] {
+            assert_eq!(
+                overlaps(&diamond, min, max),
+                overlapping,
+                "{min:?}, {max:?}"
+            );
+            assert_eq!(
+                overlaps(&reversed, min, max),
+                overlapping,
+                "{min:?}, {max:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn needs_every_tile_that_is_read() {
+        let (width, height) = (3 * TILE + 50, 2 * TILE + 10);
+        let (columns, rows) = (width.div_ceil(TILE), height.div_ceil(TILE));
+        let (out_width, out_height) = (width, height);
+
+        let center = (width as f32 / 2.0, height as f32 / 2.0);
+        let matrices = [
+            transform::around(&transform::rotation(0.5), center),
+            transform::around(&transform::scale(0.3, 0.4), center),
+            perspective((width, height)),
+        ];
+
+        let mut samplings = Vec::new();
+        for (filter, antialias, edges) in [
+            (
+                Filter::Nearest,
+                Antialias::None,
+                &[Edge::Transparent, Edge::Clamp, Edge::Repeat, Edge::Mirror][..],
+            ),
+            (Filter::Lanczos3, Antialias::None, &[Edge::Transparent][..]),
+            (
+                Filter::Bilinear,
+                Antialias::Supersample,
+                &[Edge::Repeat][..],
+            ),
+            (Filter::Mitchell, Antialias::Footprint, &[Edge::Mirror][..]),
+        ] {
+            for &edge in edges {
+                let mut sampling = sampling::<u8>(filter);
+                sampling.antialias = antialias;
+                sampling.border.edge = edge;
+                samplings.push(sampling);
+            }
+        }
+
+        let mut recorder = Recorder {
+            image: pattern((width, height), 3),
+            columns,
+            read: Vec::new(),
+        };
+
+        for matrix in &matrices {
+            let mut inverse = matrix.clone();
+            inverse.invert().unwrap();
+
+            for sampling in &samplings {
+                let mut left_out = 0;
+
+                // Thin bands all over the output image, which end up close to the edges of tiles
+                for first_row in (0..out_height - 4).step_by(23) {
+                    let band = (first_row, first_row + 4);
+                    recorder.read = (0..columns * rows)
+                        .map(|_| AtomicBool::new(false))
+                        .collect();
+
+                    let mut output = images::filled((out_width, band.1 - band.0), 4, [0; 4]);
+                    let pb = ProgressBar::hidden();
+                    render::gather(
+                        &recorder,
+                        &mut output,
+                        band.0,
+                        out_height,
+                        &inverse,
+                        sampling,
+                        &pb,
+                    );
+
+                    let needed = needed_tiles(
+                        &inverse,
+                        (width, height),
+                        band,
+                        (out_width, out_height),
+                        sampling,
+                    );
+                    for (tile, (read, needed)) in recorder.read.iter().zip(&needed).enumerate() {
+                        let read = read.load(Ordering::Relaxed);
+                        let context = format!("{matrix}, {sampling:?}, {band:?}");
+                        assert!(
+                            !read || *needed,
+                            "tile {tile} is read, but not needed, {context}"
+                        );
+                    }
+
+                    left_out += needed.iter().filter(|&&needed| !needed).count();
+                }
+
+                // Nothing is read beyond the edges of transparent images, so the bands only read
+                // the tiles around them
+                if sampling.border.edge == Edge::Transparent {
+                    assert!(left_out > 0, "{matrix}, {sampling:?}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn plans_bands_within_the_budget() {
+        // Every 100 rows read another tile
+        let needed = |(first_row, last_row): (usize, usize)| {
+            (0..10)
+                .map(|tile| (first_row / 100..=(last_row - 1) / 100).contains(&tile))
+                .collect()
+        };
+
+        let bands = plan_bands(1000, (10, 1000), (3000, 500), needed).unwrap();
+
+        let mut first_row = 0;
+        for &(first, last) in &bands {
+            assert_eq!(first, first_row);
+            assert!(last > first);
+
+            let tiles = needed((first, last)).iter().filter(|&&n| n).count();
+            assert!(
+                tiles * 1000 + (last - first) * 10 <= 2500,
+                "{first}..{last}"
+            );
+            first_row = last;
+        }
+        assert_eq!(first_row, 1000);
+
+        // The bands are halved until they fit, and grow again once they leave most of the budget
+        // unused, up to half of it
+        let needed = |(first_row, _)| vec![first_row < 10; 2];
+        let bands = plan_bands(1000, (10, 1000), (3000, 500), needed).unwrap();
+        assert_eq!(
+            bands[..5],
+            [(0, 31), (31, 62), (62, 124), (124, 248), (248, 372)]
+        );
+
+        // A single row that reads 3 tiles doesn't fit
+        let error = plan_bands(1000, (10, 1000), (3000, 500), |_| vec![true; 3]).unwrap_err();
+        assert!(
+            error.to_string().contains("rendering row 0 alone"),
+            "{error}"
+        );
+    }
+
+    /// Renders the `input` image at `path` through `matrix` into a `width`x`height` image, once
+    /// in memory and once in bands within the `budget`, and checks that they come out the same
+    fn check_bands<T: Sample>(
+        input: &ImageArray<T>,
+        path: &Path,
+        (matrix, (width, height)): (&Array2<f32>, (usize, usize)),
+        sampling: &Sampling,
+        budget: usize,
+    ) {
+        write(input, path);
+        let input = read::<T>(path);
+
+        let mut inverse = matrix.clone();
+        inverse.invert().unwrap();
+        let in_memory = gathered(&input, (width, height), &inverse, &sampling.convert());
+
+        let banded_path = path.with_extension("banded.tif");
+        let output = Output {
+            path: &banded_path,
+            dimensions: (width, height),
+            layout: T::wrap(in_memory.clone()).layout(),
+            background: [0; 4],
+        };
+        let mut reader = open(path).unwrap();
+        render(reader.as_mut(), &output, &inverse, sampling, budget).unwrap();
+
+        assert_eq!(read::<T>(&banded_path), in_memory, "{}", path.display());
+    }
+
+    #[test]
+    fn renders_bands_like_whole_images() {
+        let scratch = Scratch::new("bands");
+        let dims = (2 * TILE + 60, TILE + 80);
+        let center = (dims.0 as f32 / 2.0, dims.1 as f32 / 2.0);
+        let rotation = transform::around(&transform::rotation(0.4), center);
+        let tilt = perspective(dims);
+        let shrunk = transform::around(&transform::scale(0.45, 0.6), center);
+
+        let mut bilinear = sampling(Filter::Bilinear);
+        bilinear.border.edge = Edge::Mirror;
+        let bicubic = sampling(Filter::Bicubic);
+        let mut footprint = sampling(Filter::Bilinear);
+        footprint.antialias = Antialias::Footprint;
+        footprint.border.edge = Edge::Clamp;
+
+        check_bands(
+            &pattern::<u8>(dims, 3),
+            &scratch.path("rgb.png"),
+            (&rotation, dims),
+            &bicubic,
+            STRIP_BYTES + (1 << 20),
+        );
+        check_bands(
+            &pattern::<u16>(dims, 4),
+            &scratch.path("rgba16.png"),
+            (&tilt, (dims.0 + 100, dims.1)),
+            &bilinear,
+            STRIP_BYTES + (4 << 20),
+        );
+        check_bands(
+            &pattern::<u16>(dims, 3),
+            &scratch.path("rgb16.ppm"),
+            (&shrunk, dims),
+            &footprint,
+            STRIP_BYTES + (3 << 20),
+        );
+        check_bands(
+            &pattern::<f32>(dims, 1),
+            &scratch.path("gray.tif"),
+            (&rotation, dims),
+            &footprint,
+            STRIP_BYTES + (3 << 20),
+        );
+        check_bands(
+            &pattern::<f32>(dims, 2),
+            &scratch.path("gray_alpha.tif"),
+            (&tilt, dims),
+            &bicubic,
+            STRIP_BYTES + (6 << 20),
+        );
+    }
+
+    #[test]
+    fn saves_bands_like_whole_images() {
+        let scratch = Scratch::new("save");
+        let dims = (700, 530);
+        let input = pattern::<u16>(dims, 4);
+        let path = scratch.path("input.png");
+        write(&input, &path);
+
+        let matrix = transform::around(&transform::rotation(0.3), (350.0, 265.0));
+        let mut inverse = matrix.clone();
+        inverse.invert().unwrap();
+        let sampling = sampling(Filter::Bilinear);
+        let in_memory = gathered(&input, dims, &inverse, &sampling.convert());
+
+        for extension in ["tif", "pam", "png"] {
+            let whole = scratch.path(&format!("whole.{extension}"));
+            images::save_image(AnyImage::U16(in_memory.clone()), &whole, None).unwrap();
+
+            let banded = scratch.path(&format!("banded.{extension}"));
+            let output = Output {
+                path: &banded,
+                dimensions: dims,
+                layout: AnyImage::U16(in_memory.clone()).layout(),
+                background: [0; 4],
+            };
+            let mut reader = open(&path).unwrap();
+            render(reader.as_mut(), &output, &inverse, &sampling, 4 << 20).unwrap();
+
+            let (whole, banded) = (
+                std::fs::read(whole).unwrap(),
+                std::fs::read(banded).unwrap(),
+            );
+            if extension == "png" {
+                // The image crate compresses whole images differently, the pixels are the same
+                let decoded = |bytes: &[u8]| {
+                    image::load_from_memory(bytes)
+                        .unwrap()
+                        .into_rgba16()
+                        .into_raw()
+                };
+                assert_eq!(decoded(&whole), decoded(&banded));
+            } else {
+                assert!(whole == banded, "{extension}");
+            }
+        }
+    }
+
+    #[test]
+    fn writes_images_through_partial_files() {
+        let scratch = Scratch::new("partial");
+        let path = scratch.path("image.pam");
+        let partial = scratch.path("image.pam.part");
+        let layout = Layout {
+            depth: Depth::Eight,
+            channels: 3,
+        };
+
+        write_image(&path, (2, 1), layout, |write| write(&[1u8, 2, 3, 4, 5, 6])).unwrap();
+        let written = std::fs::read(&path).unwrap();
+        assert!(written.ends_with(&[1, 2, 3, 4, 5, 6]));
+        assert!(!partial.exists());
+
+        // A failed image neither replaces the one that's there nor is left behind
+        let failed = write_image::<u8, ()>(&path, (2, 1), layout, |write| {
+            write(&[7, 8, 9])?;
+            bail!("failed halfway")
+        });
+        assert!(failed.is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), written);
+        assert!(!partial.exists());
+    }
+
+    #[test]
+    fn writes_tiff_strips_across_bands() {
+        let scratch = Scratch::new("strips");
+        let path = scratch.path("image.tif");
+
+        // The strips are 125 rows tall, so bands of 37 rows end in the middle of them
+        let (width, height) = (1000, 300);
+        let image = pattern::<u16>((width, height), 2);
+        let layout = Layout {
+            depth: Depth::Sixteen,
+            channels: 2,
+        };
+
+        let ((), written) = write_image(&path, (width, height), layout, |write| {
+            for band in image.as_slice().unwrap().chunks(37 * width * 2) {
+                write(band)?;
+            }
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(written.channels, 4);
+
+        let mut decoder = TiffDecoder::new(File::open(&path).unwrap()).unwrap();
+        assert_eq!(decoder.get_tag_u32(Tag::RowsPerStrip).unwrap(), 125);
+        assert_eq!(decoder.strip_count().unwrap(), 3);
+
+        let expected = AnyImage::U16(image).converted::<u16>(4);
+        assert_eq!(read::<u16>(&path), expected);
+    }
+}
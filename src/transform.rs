@@ -0,0 +1,225 @@
+use ndarray::Array2;
+
+use crate::expr;
+
+/// The point that rotations, scales, shears and reflections are performed around
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) enum Pivot {
+    /// The bottom left corner of the input image
+    #[default]
+    Origin,
+
+    /// The center of the input image
+    Center,
+
+    /// A point in pixels from the top left corner of the input image
+    Point(f32, f32),
+}
+
+impl Pivot {
+    /// The position of the pivot with Y pointing up, in an image of the given size
+    pub(crate) fn position(self, (width, height): (usize, usize)) -> (f32, f32) {
+        match self {
+            Pivot::Origin => (0.0, 0.0),
+            Pivot::Center => ((width - 1) as f32 / 2.0, (height - 1) as f32 / 2.0),
+            Pivot::Point(x, y) => (x, (height - 1) as f32 - y),
+        }
+    }
+}
+
+pub(crate) fn parse_pivot(s: &str) -> Result<Pivot, String> {
+    match s.trim() {
+        "origin" => Ok(Pivot::Origin),
+        "center" => Ok(Pivot::Center),
+        s => {
+            let [x, y] = crate::parse_nums::<f32, 2>(s)?;
+            Ok(Pivot::Point(x, y))
+        }
+    }
+}
+
+/// Parses an angle expression in degrees, or with units (`deg`, `°` or `rad`), into radians
+pub(crate) fn parse_angle(s: &str) -> Result<f32, String> {
+    expr::evaluate_angle(s)
+        .map(|angle| angle as f32)
+        .map_err(|e| e.display(s))
+}
+
+/// Creates a homogeneous 3x3 matrix from its rows
+fn matrix(rows: [[f32; 3]; 3]) -> Array2<f32> {
+    Array2::from_shape_vec((3, 3), rows.concat()).unwrap()
+}
+
+/// Creates a homogeneous 3x3 matrix from values given column by column: a 2x2 matrix
+/// (Xx,Xy,Yx,Yy), optionally followed by a translation (Tx,Ty), or a full 3x3 matrix
+pub(crate) fn from_columns(values: &[f32]) -> Result<Array2<f32>, String> {
+    let columns = match *values {
+        [xx, xy, yx, yy] => [xx, xy, 0.0, yx, yy, 0.0, 0.0, 0.0, 1.0],
+        [xx, xy, yx, yy, tx, ty] => [xx, xy, 0.0, yx, yy, 0.0, tx, ty, 1.0],
+        [xx, xy, xw, yx, yy, yw, tx, ty, tw] => [xx, xy, xw, yx, yy, yw, tx, ty, tw],
+        _ => {
+            return Err(format!(
+                "Expected 4, 6 or 9 elements, got {} ({:?})",
+                values.len(),
+                values
+            ))
+        }
+    };
+
+    Ok(Array2::from_shape_vec((3, 3), columns.to_vec())
+        .unwrap()
+        .reversed_axes()
+        .as_standard_layout()
+        .into_owned())
+}
+
+pub(crate) fn identity() -> Array2<f32> {
+    Array2::eye(3)
+}
+
+pub(crate) fn translation(x: f32, y: f32) -> Array2<f32> {
+    matrix([[1.0, 0.0, x], [0.0, 1.0, y], [0.0, 0.0, 1.0]])
+}
+
+/// A counter-clockwise rotation by `angle` radians
+pub(crate) fn rotation(angle: f32) -> Array2<f32> {
+    // Snapping to whole numbers keeps multiples of 90° exact (adding 0 gets rid of -0)
+    let snap = |v: f64| {
+        if (v - v.round()).abs() < 1e-6 {
+            v.round() + 0.0
+        } else {
+            v
+        }
+    } as f32;
+    let (sin, cos) = (angle as f64).sin_cos();
+    let (sin, cos) = (snap(sin), snap(cos));
+
+    matrix([[cos, -sin + 0.0, 0.0], [sin, cos, 0.0], [0.0, 0.0, 1.0]])
+}
+
+pub(crate) fn scale(x: f32, y: f32) -> Array2<f32> {
+    matrix([[x, 0.0, 0.0], [0.0, y, 0.0], [0.0, 0.0, 1.0]])
+}
+
+/// Shifts X by `x` times Y, and Y by `y` times X
+pub(crate) fn shear(x: f32, y: f32) -> Array2<f32> {
+    matrix([[1.0, x, 0.0], [y, 1.0, 0.0], [0.0, 0.0, 1.0]])
+}
+
+/// Mirrors across the X axis (flipping Y) and/or the Y axis (flipping X)
+pub(crate) fn reflection(across_x: bool, across_y: bool) -> Array2<f32> {
+    let flip = |flip: bool| if flip { -1.0 } else { 1.0 };
+
+    scale(flip(across_y), flip(across_x))
+}
+
+/// Performs `matrix` around the point (`x`, `y`) instead of the origin
+pub(crate) fn around(matrix: &Array2<f32>, (x, y): (f32, f32)) -> Array2<f32> {
+    translation(x, y).dot(matrix).dot(&translation(-x, -y))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+
+    use super::*;
+    use crate::render::transform_point;
+
+    #[test]
+    fn parses_pivots() {
+        assert_eq!(parse_pivot("origin"), Ok(Pivot::Origin));
+        assert_eq!(parse_pivot(" center "), Ok(Pivot::Center));
+        assert_eq!(parse_pivot("10, 4*5"), Ok(Pivot::Point(10.0, 20.0)));
+        assert!(parse_pivot("10")
+            .unwrap_err()
+            .contains("Expected 2 elements, got 1"));
+        assert!(parse_pivot("middle").is_err());
+
+        // Points are given from the top left corner
+        assert_eq!(Pivot::Origin.position((41, 31)), (0.0, 0.0));
+        assert_eq!(Pivot::Center.position((41, 31)), (20.0, 15.0));
+        assert_eq!(Pivot::Point(10.0, 20.0).position((41, 31)), (10.0, 10.0));
+    }
+
+    #[test]
+    fn parses_angles_in_degrees_unless_given_a_unit() {
+        let close = |s: &str, radians: f32| {
+            let angle = parse_angle(s).unwrap();
+            assert!((angle - radians).abs() < 1e-6, "{s}: {angle}");
+        };
+
+        close("90", PI / 2.0);
+        close("-45deg", -PI / 4.0);
+        close("180°", PI);
+        close("0.5rad", 0.5);
+        close("30 + 15", PI / 4.0);
+        assert!(parse_angle("90 degrees").is_err());
+        assert!(parse_angle("").is_err());
+    }
+
+    #[test]
+    fn reads_matrices_column_by_column() {
+        assert_eq!(
+            from_columns(&[1.0, 2.0, 3.0, 4.0]).unwrap(),
+            matrix([[1.0, 3.0, 0.0], [2.0, 4.0, 0.0], [0.0, 0.0, 1.0]])
+        );
+        assert_eq!(
+            from_columns(&[1.0, 2.0, 3.0, 4.0, 5.0, 6.0]).unwrap(),
+            matrix([[1.0, 3.0, 5.0], [2.0, 4.0, 6.0], [0.0, 0.0, 1.0]])
+        );
+        assert_eq!(
+            from_columns(&[1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0]).unwrap(),
+            matrix([[1.0, 4.0, 7.0], [2.0, 5.0, 8.0], [3.0, 6.0, 9.0]])
+        );
+
+        for values in [&[][..], &[1.0; 3], &[1.0; 5], &[1.0; 8], &[1.0; 10]] {
+            let error = from_columns(values).unwrap_err();
+            assert!(
+                error.starts_with(&format!(
+                    "Expected 4, 6 or 9 elements, got {}",
+                    values.len()
+                )),
+                "{error}"
+            );
+        }
+    }
+
+    #[test]
+    fn rotates_exactly_by_multiples_of_90_degrees() {
+        let turns = [
+            [[1.0, 0.0], [0.0, 1.0]],
+            [[0.0, -1.0], [1.0, 0.0]],
+            [[-1.0, 0.0], [0.0, -1.0]],
+            [[0.0, 1.0], [-1.0, 0.0]],
+        ];
+
+        for quarter in -8_i32..=8 {
+            let [[xx, xy], [yx, yy]] = turns[quarter.rem_euclid(4) as usize];
+            let expected = matrix([[xx, xy, 0.0], [yx, yy, 0.0], [0.0, 0.0, 1.0]]);
+            let rotation = rotation(quarter as f32 * PI / 2.0);
+
+            // Comparing the bits also tells 0 and -0 apart
+            let bits = |matrix: &Array2<f32>| matrix.map(|v| v.to_bits());
+            assert_eq!(bits(&rotation), bits(&expected), "{quarter} quarter turns");
+        }
+
+        // Angles close to them aren't snapped
+        let rotation = rotation(PI / 2.0 + 1e-4);
+        assert!((rotation[[0, 0]] + 1e-4).abs() < 1e-7, "{rotation}");
+        assert_eq!(rotation[[1, 0]], 1.0);
+    }
+
+    #[test]
+    fn transforms_around_a_point() {
+        let turn = around(&rotation(PI / 2.0), (3.0, 1.0));
+        assert_eq!(transform_point(&turn, 3.0, 1.0), Some((3.0, 1.0)));
+        assert_eq!(transform_point(&turn, 4.0, 1.0), Some((3.0, 2.0)));
+        assert_eq!(transform_point(&turn, 3.0, 3.0), Some((1.0, 1.0)));
+
+        let grow = around(&scale(2.0, 3.0), (1.0, 1.0));
+        assert_eq!(transform_point(&grow, 1.0, 1.0), Some((1.0, 1.0)));
+        assert_eq!(transform_point(&grow, 2.0, 2.0), Some((3.0, 4.0)));
+
+        assert_eq!(around(&identity(), (5.0, -2.0)), identity());
+    }
+}